@@ -1,4 +1,36 @@
-use std::net::{SocketAddr, ToSocketAddrs};
+use crate::{
+	apperr::{ConfigError, ConfigIssue},
+	joinpolicy::JoinPolicyKind,
+};
+
+use std::{
+	collections::HashSet,
+	fs,
+	net::{SocketAddr, ToSocketAddrs},
+	sync::Arc,
+};
+
+use {
+	arc_swap::ArcSwap,
+	sha2::{Digest, Sha256},
+	subtle::ConstantTimeEq,
+};
+
+/// Where to read the config from
+/// Kept so reloads read the same file with the same overrides
+#[derive(Debug, Clone, Default)]
+pub struct ConfigSource {
+	/// Config file path
+	/// `Titanfront.*` in the working directory when unset
+	pub path: Option<String>,
+	/// Values set on the command line
+	/// These win over both the file and the environment
+	pub overrides: Vec<(&'static str, config::Value)>,
+}
+
+/// Config shared between tasks
+/// Reloading swaps in a new AppConfig without disturbing readers
+pub type SharedConfig = Arc<ArcSwap<AppConfig>>;
 
 /// App
 // Modification of this object is not persisted
@@ -6,8 +38,11 @@ use std::net::{SocketAddr, ToSocketAddrs};
 pub struct AppConfig {
 	/// Server encryption key
 	pub key: Vec<u8>,
-	/// UDP interface and port Titanfront should expose
-	pub udp_address: SocketAddr,
+	/// UDP interfaces and ports Titanfront should expose
+	/// Read from `udp_addresses` or the single `udp_address` when that is unset.
+	/// The first port is the one advertised to the master server.
+	/// An IPv6 address only takes IPv6 players when an IPv4 address shares its port.
+	pub udp_addresses: Vec<SocketAddr>,
 	/// HTTP interface and port Titanfront should expose
 	pub auth_address: SocketAddr,
 	/// Address for binding relays.
@@ -17,26 +52,157 @@ pub struct AppConfig {
 	pub player_count: usize,
 	/// Size of the TCP buffer
 	pub receive_buf_size: usize,
+	/// Datagrams read or sent per system call on Linux
+	/// 1 reads one datagram at a time
+	pub udp_batch_size: usize,
 	/// Array of admin usernames
 	pub admins: Vec<u64>,
+	/// Users permitted to join a private server
+	/// Merged from `allowed_users` and `allowed_users_file`
+	/// Everyone may join when neither is set
+	pub allowed_users: Option<HashSet<u64>>,
 	/// List of servers addresses to proxy to
 	pub target_servers: Vec<SocketAddr>,
 	/// Which server should new players spawn in
+	/// Only used by the fixed join policy
 	pub join_target: usize,
+	/// How new players are spread across target servers
+	pub join_policy: JoinPolicyKind,
+	/// Relative weight of each target server for the weighted join policy
+	pub join_weights: Vec<u64>,
+	/// Seconds the sticky join policy remembers a player after they leave
+	pub sticky_ttl: u64,
 	/// Whether to use central authentication
 	pub auth_enabled: bool,
 	/// URL of the central auth server
 	pub auth_server: String,
+	/// Path of the JSON file bans are stored in
+	/// Bans are not persisted when this is empty
+	pub ban_file: String,
+	/// Where connection events are written as JSON lines
+	/// `-` is stdout. Events are not recorded when this is empty
+	pub event_log: String,
+	/// Seconds to keep relaying for connected players after SIGTERM
+	pub drain_timeout: u64,
+	/// Seconds a connect handshake may take before its relay socket is reclaimed
+	pub handshake_timeout: u64,
+	/// Seconds without traffic in either direction before an authenticated player is dropped
+	pub idle_timeout: u64,
+	/// Extra seconds allowed when the target server went quiet before the player did
+	/// Covers map changes where the server stops sending for a while
+	pub backend_grace: u64,
+	/// Seconds between sweeps for idle connections and expired tokens
+	pub cleanup_interval: u64,
+	/// Bearer token for the admin API
+	/// The admin API is disabled when this is empty
+	pub admin_token: String,
 	pub name: String,
 	pub description: String,
+	/// Sent to the master server and checked against joining players
 	pub password: String,
+	/// SHA-256 digest of the password
+	/// Has to match `password` since the master server needs the plaintext
+	pub password_hash: Option<Vec<u8>>,
+	/// Seconds a player has to connect after the master server sends their token
+	pub token_ttl: u64,
+	/// Unused tokens a single user may hold
+	/// The oldest is revoked when another is issued
+	pub max_tokens_per_user: usize,
+	/// Packets per second accepted from each address without an authenticated bind
+	/// 0 turns the limit off
+	pub rate_limit_per_ip: u64,
+	pub rate_limit_per_ip_burst: u64,
+	/// Packets per second accepted from all unauthenticated addresses together
+	/// 0 turns the limit off
+	pub rate_limit_global: u64,
+	pub rate_limit_global_burst: u64,
+	/// Bad connect packets before an address is ignored
+	/// 0 turns the penalty box off
+	pub penalty_threshold: u32,
+	/// Seconds an address is ignored for
+	pub penalty_duration: u64,
+	/// Answer the first connect with a cookie and only allocate a relay once the client echoes it
+	pub connect_cookies: bool,
+	/// Seconds a connect cookie stays valid
+	pub cookie_lifetime: u64,
+	/// Wrong password attempts allowed per user before they are throttled
+	pub password_max_failures: u32,
+	/// Seconds a throttled user has to wait
+	pub password_lockout: u64,
 	// TODO: derive this rather than setting it
 	pub version: String,
 	pub modinfo: String,
+	/// Reload when the config file changes
+	pub watch_config: bool,
+}
+
+// The getters below record a problem and return a placeholder so loading can carry on
+
+fn get_str(conf: &config::Config, issues: &mut Vec<ConfigIssue>, name: &'static str) -> String {
+	match conf.get_str(name) {
+		Ok(s) => s,
+		Err(e) => {
+			issues.push(ConfigIssue::BadValue(name, e.to_string()));
+			String::new()
+		}
+	}
+}
+
+fn get_uint(conf: &config::Config, issues: &mut Vec<ConfigIssue>, name: &'static str) -> u64 {
+	match conf.get_int(name) {
+		Ok(i) if i >= 0 => i as u64,
+		Ok(i) => {
+			issues.push(ConfigIssue::BadValue(name, i.to_string()));
+			0
+		}
+		Err(e) => {
+			issues.push(ConfigIssue::BadValue(name, e.to_string()));
+			0
+		}
+	}
+}
+
+fn get_bool(conf: &config::Config, issues: &mut Vec<ConfigIssue>, name: &'static str) -> bool {
+	match conf.get_bool(name) {
+		Ok(b) => b,
+		Err(e) => {
+			issues.push(ConfigIssue::BadValue(name, e.to_string()));
+			false
+		}
+	}
+}
+
+fn get_addr(
+	conf: &config::Config,
+	issues: &mut Vec<ConfigIssue>,
+	name: &'static str,
+) -> SocketAddr {
+	let s = get_str(conf, issues, name);
+	match s.parse() {
+		Ok(addr) => addr,
+		Err(_) => {
+			if !s.is_empty() {
+				issues.push(ConfigIssue::BadValue(name, s));
+			}
+			SocketAddr::from(([0, 0, 0, 0], 0))
+		}
+	}
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+	if !s.len().is_multiple_of(2) {
+		return None;
+	}
+	(0..s.len())
+		.step_by(2)
+		.map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+		.collect()
 }
 
 impl AppConfig {
-	pub fn new() -> AppConfig {
+	/// Read the config file and environment
+	/// Every problem found is reported together rather than stopping at the first
+	pub fn load(source: &ConfigSource) -> Result<AppConfig, ConfigError> {
 		let mut conf = config::Config::default();
 
 		log::info!("Setting defaults");
@@ -50,13 +216,35 @@ impl AppConfig {
 
 		conf.set_default("receive_buf_size", 2048).unwrap();
 
+		conf.set_default("udp_batch_size", 1).unwrap();
+
 		conf.set_default("join_target", 0).unwrap();
 
+		conf.set_default("join_policy", "fixed").unwrap();
+
+		conf.set_default("sticky_ttl", 3600).unwrap();
+
 		conf.set_default("auth_enabled", true).unwrap();
 
 		conf.set_default("auth_server", "https://northstar.tf")
 			.unwrap();
 
+		conf.set_default("ban_file", "bans.json").unwrap();
+
+		conf.set_default("event_log", "").unwrap();
+
+		conf.set_default("drain_timeout", 60).unwrap();
+
+		conf.set_default("handshake_timeout", 10).unwrap();
+
+		conf.set_default("idle_timeout", 5).unwrap();
+
+		conf.set_default("backend_grace", 10).unwrap();
+
+		conf.set_default("cleanup_interval", 5).unwrap();
+
+		conf.set_default("admin_token", "").unwrap();
+
 		conf.set_default("name", "Titanfront server").unwrap();
 
 		conf.set_default("description", "Titanfront server")
@@ -64,8 +252,36 @@ impl AppConfig {
 
 		conf.set_default("password", "").unwrap();
 
+		conf.set_default("password_hash", "").unwrap();
+
+		conf.set_default("token_ttl", 60).unwrap();
+
+		conf.set_default("max_tokens_per_user", 2).unwrap();
+
+		conf.set_default("rate_limit_per_ip", 20).unwrap();
+
+		conf.set_default("rate_limit_per_ip_burst", 40).unwrap();
+
+		conf.set_default("rate_limit_global", 2000).unwrap();
+
+		conf.set_default("rate_limit_global_burst", 4000).unwrap();
+
+		conf.set_default("penalty_threshold", 10).unwrap();
+
+		conf.set_default("penalty_duration", 60).unwrap();
+
+		conf.set_default("connect_cookies", false).unwrap();
+
+		conf.set_default("cookie_lifetime", 30).unwrap();
+
+		conf.set_default("password_max_failures", 5).unwrap();
+
+		conf.set_default("password_lockout", 60).unwrap();
+
 		conf.set_default("version", "").unwrap();
 
+		conf.set_default("watch_config", true).unwrap();
+
 		conf
 			.set_default(
 				"modinfo",
@@ -74,23 +290,93 @@ impl AppConfig {
 			.unwrap();
 
 		log::info!("Merging configuration");
-		conf.merge(config::File::with_name("Titanfront"))
-			.unwrap()
-			.merge(config::Environment::with_prefix("Titanfront"))
-			.unwrap();
+		// Nothing else can be checked without the sources
+		let file = source.path.as_deref().unwrap_or("Titanfront");
+		if let Err(e) = conf
+			.merge(config::File::with_name(file))
+			.and_then(|c| c.merge(config::Environment::with_prefix("Titanfront")))
+		{
+			return Err(ConfigError(vec![ConfigIssue::Source(e)]));
+		}
+		for (key, value) in &source.overrides {
+			if let Err(e) = conf.set(key, value.clone()) {
+				return Err(ConfigError(vec![ConfigIssue::Source(e)]));
+			}
+		}
 
 		log::info!("Building configuration struct");
+		let mut issues: Vec<ConfigIssue> = Vec::new();
+
 		let mut admins: Vec<u64> = Vec::new();
 		if let Ok(ads) = conf.get_array("admins") {
 			for ad in ads {
 				if let Ok(s) = ad.into_str() {
 					match s.parse::<u64>() {
+						Ok(u) if admins.contains(&u) => issues.push(ConfigIssue::DuplicateAdmin(u)),
 						Ok(u) => admins.push(u),
-						Err(_) => panic!("Bad admin ID number"),
+						Err(_) => issues.push(ConfigIssue::BadValue("admins", s)),
+					}
+				}
+			}
+		}
+
+		let mut udp_addresses: Vec<SocketAddr> = Vec::new();
+		if let Ok(addrs) = conf.get_array("udp_addresses") {
+			for addr in addrs {
+				if let Ok(s) = addr.into_str() {
+					match s.parse() {
+						Ok(a) if udp_addresses.contains(&a) => issues.push(ConfigIssue::BadValue(
+							"udp_addresses",
+							format!("{} is listed twice", a),
+						)),
+						Ok(a) => udp_addresses.push(a),
+						Err(_) => issues.push(ConfigIssue::BadValue("udp_addresses", s)),
+					}
+				}
+			}
+			if udp_addresses.is_empty() {
+				issues.push(ConfigIssue::Missing("udp_addresses"));
+			}
+		} else {
+			udp_addresses.push(get_addr(&conf, &mut issues, "udp_address"));
+		}
+
+		let mut allowed: Option<HashSet<u64>> = None;
+		if let Ok(us) = conf.get_array("allowed_users") {
+			let set = allowed.get_or_insert_with(HashSet::new);
+			for u in us {
+				if let Ok(s) = u.into_str() {
+					match s.parse::<u64>() {
+						Ok(u) => {
+							set.insert(u);
+						}
+						Err(_) => issues.push(ConfigIssue::BadValue("allowed_users", s)),
 					}
 				}
 			}
 		}
+		if let Ok(path) = conf.get_str("allowed_users_file") {
+			let set = allowed.get_or_insert_with(HashSet::new);
+			match fs::read_to_string(&path) {
+				Ok(contents) => {
+					// One ID per line with # comments
+					for line in contents.lines() {
+						let line = line.split('#').next().unwrap_or("").trim();
+						if line.is_empty() {
+							continue;
+						}
+						match line.parse::<u64>() {
+							Ok(u) => {
+								set.insert(u);
+							}
+							Err(_) => issues
+								.push(ConfigIssue::BadValue("allowed_users_file", line.to_owned())),
+						}
+					}
+				}
+				Err(e) => issues.push(ConfigIssue::File(path, e)),
+			}
+		}
 
 		let mut servers: Vec<SocketAddr> = Vec::new();
 		if let Ok(servs) = conf.get_array("target_servers") {
@@ -98,83 +384,384 @@ impl AppConfig {
 				if let Ok(s) = serv.into_str() {
 					match s.parse() {
 						Ok(addr) => servers.push(addr),
-						Err(_) => match s.to_socket_addrs() {
-							Ok(mut itr) => servers.push(itr.next().unwrap()),
-							Err(_) => panic!("Bad target address"),
+						Err(_) => match s.to_socket_addrs().map(|mut itr| itr.next()) {
+							Ok(Some(addr)) => servers.push(addr),
+							_ => issues.push(ConfigIssue::Unresolved(s)),
 						},
 					}
 				}
 			}
 		}
 		if servers.is_empty() {
-			panic!("No target servers to proxy")
+			issues.push(ConfigIssue::NoTargets());
 		}
 
-		AppConfig {
-			key: match conf.get_str("key") {
-				Ok(ks) => base64::decode(ks).expect("Bad server key"),
-				Err(_) => panic!("Did not specify server encryption key"),
-			},
-			udp_address: match conf.get_str("udp_address") {
-				Ok(saddr) => match saddr.parse() {
-					Ok(addr) => addr,
-					Err(_) => panic!("Bad udp address"),
-				},
-				Err(_) => panic!("Bad udp address"),
-			},
-			auth_address: match conf.get_str("auth_address") {
-				Ok(saddr) => match saddr.parse() {
-					Ok(addr) => addr,
-					Err(_) => panic!("Bad auth address"),
-				},
-				Err(_) => panic!("Bad auth address"),
-			},
-			relay_address: match conf.get_str("relay_address") {
-				Ok(saddr) => saddr,
-				Err(_) => panic!("Bad relay address"),
-			},
-			player_count: match conf.get_int("player_count") {
-				Ok(p) => p as usize,
-				Err(_) => panic!("Player number is not an int"),
+		let join_target = get_uint(&conf, &mut issues, "join_target") as usize;
+		if !servers.is_empty() && join_target >= servers.len() {
+			issues.push(ConfigIssue::JoinTargetRange(join_target, servers.len()));
+		}
+
+		let mut weights: Vec<u64> = Vec::new();
+		if let Ok(ws) = conf.get_array("join_weights") {
+			for w in ws {
+				match w.into_int() {
+					Ok(i) if i >= 0 => weights.push(i as u64),
+					Ok(i) => issues.push(ConfigIssue::BadValue("join_weights", i.to_string())),
+					Err(e) => issues.push(ConfigIssue::BadValue("join_weights", e.to_string())),
+				}
+			}
+		}
+		// No weights means every server is weighted equally
+		if !weights.is_empty() && weights.len() != servers.len() {
+			issues.push(ConfigIssue::WeightCount(weights.len(), servers.len()));
+		}
+
+		let key = match conf.get_str("key") {
+			Ok(ks) => match base64::decode(ks) {
+				// Aes128Gcm panics on any other length
+				Ok(k) if k.len() == 16 => k,
+				Ok(k) => {
+					issues.push(ConfigIssue::KeyLength(k.len()));
+					Vec::new()
+				}
+				Err(e) => {
+					issues.push(ConfigIssue::BadValue("key", e.to_string()));
+					Vec::new()
+				}
 			},
-			receive_buf_size: match conf.get_int("receive_buf_size") {
-				Ok(s) => s as usize,
-				Err(_) => panic!("Buffer size is not an int"),
+			Err(_) => {
+				issues.push(ConfigIssue::Missing("key"));
+				Vec::new()
+			}
+		};
+
+		let join_policy = match conf.get_str("join_policy").as_deref() {
+			Ok("fixed") => JoinPolicyKind::Fixed,
+			Ok("round_robin") => JoinPolicyKind::RoundRobin,
+			Ok("least_connected") => JoinPolicyKind::LeastConnected,
+			Ok("weighted") => JoinPolicyKind::Weighted,
+			Ok("sticky") => JoinPolicyKind::Sticky,
+			Ok(p) => {
+				issues.push(ConfigIssue::BadValue("join_policy", p.to_owned()));
+				JoinPolicyKind::Fixed
+			}
+			Err(e) => {
+				issues.push(ConfigIssue::BadValue("join_policy", e.to_string()));
+				JoinPolicyKind::Fixed
+			}
+		};
+
+		let password_hash = match conf.get_str("password_hash") {
+			Ok(s) if s.is_empty() => None,
+			Ok(s) => match decode_hex(&s) {
+				Some(h) if h.len() == 32 => Some(h),
+				_ => {
+					issues.push(ConfigIssue::BadValue(
+						"password_hash",
+						String::from("not a hex SHA-256 digest"),
+					));
+					None
+				}
 			},
+			Err(e) => {
+				issues.push(ConfigIssue::BadValue("password_hash", e.to_string()));
+				None
+			}
+		};
+		let password = get_str(&conf, &mut issues, "password");
+		// The master server needs the plaintext to ask players for it
+		// A hash that disagrees would lock everyone out without a word
+		let hash_mismatch = password_hash
+			.as_ref()
+			.is_some_and(|h| Sha256::digest(password.as_bytes()).as_slice() != h.as_slice());
+		if hash_mismatch {
+			issues.push(ConfigIssue::BadValue(
+				"password_hash",
+				String::from("is not the SHA-256 digest of password"),
+			));
+		}
+
+		let handshake_timeout = get_uint(&conf, &mut issues, "handshake_timeout");
+		if handshake_timeout == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"handshake_timeout",
+				String::from("must be at least 1"),
+			));
+		}
+		let idle_timeout = get_uint(&conf, &mut issues, "idle_timeout");
+		if idle_timeout == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"idle_timeout",
+				String::from("must be at least 1"),
+			));
+		}
+
+		let cleanup_interval = get_uint(&conf, &mut issues, "cleanup_interval");
+		if cleanup_interval == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"cleanup_interval",
+				String::from("must be at least 1"),
+			));
+		}
+
+		let token_ttl = get_uint(&conf, &mut issues, "token_ttl");
+		if token_ttl == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"token_ttl",
+				String::from("must be at least 1"),
+			));
+		}
+		let max_tokens_per_user = get_uint(&conf, &mut issues, "max_tokens_per_user") as usize;
+		if max_tokens_per_user == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"max_tokens_per_user",
+				String::from("must be at least 1"),
+			));
+		}
+
+		let rate_limit_per_ip = get_uint(&conf, &mut issues, "rate_limit_per_ip");
+		let rate_limit_per_ip_burst = get_uint(&conf, &mut issues, "rate_limit_per_ip_burst");
+		let rate_limit_global = get_uint(&conf, &mut issues, "rate_limit_global");
+		let rate_limit_global_burst = get_uint(&conf, &mut issues, "rate_limit_global_burst");
+		// An empty bucket would drop everything
+		if rate_limit_per_ip > 0 && rate_limit_per_ip_burst == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"rate_limit_per_ip_burst",
+				String::from("must be at least 1"),
+			));
+		}
+		if rate_limit_global > 0 && rate_limit_global_burst == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"rate_limit_global_burst",
+				String::from("must be at least 1"),
+			));
+		}
+
+		let sticky_ttl = get_uint(&conf, &mut issues, "sticky_ttl");
+		if sticky_ttl == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"sticky_ttl",
+				String::from("must be at least 1"),
+			));
+		}
+
+		let cookie_lifetime = get_uint(&conf, &mut issues, "cookie_lifetime");
+		if cookie_lifetime == 0 {
+			issues.push(ConfigIssue::BadValue(
+				"cookie_lifetime",
+				String::from("must be at least 1"),
+			));
+		}
+
+		let udp_batch_size = get_uint(&conf, &mut issues, "udp_batch_size") as usize;
+		if !(1..=1024).contains(&udp_batch_size) {
+			issues.push(ConfigIssue::BadValue(
+				"udp_batch_size",
+				String::from("must be between 1 and 1024"),
+			));
+		}
+
+		let config = AppConfig {
+			key,
+			udp_addresses,
+			auth_address: get_addr(&conf, &mut issues, "auth_address"),
+			relay_address: get_str(&conf, &mut issues, "relay_address"),
+			player_count: get_uint(&conf, &mut issues, "player_count") as usize,
+			receive_buf_size: get_uint(&conf, &mut issues, "receive_buf_size") as usize,
+			udp_batch_size,
 			admins,
+			allowed_users: allowed,
 			target_servers: servers,
-			join_target: match conf.get_int("join_target") {
-				Ok(t) => t as usize,
-				Err(_) => panic!("Join target is not an int"),
-			},
-			auth_enabled: match conf.get_bool("auth_enabled") {
-				Ok(b) => b,
-				Err(_) => panic!("Auth enabled is not a boolean value"),
-			},
-			auth_server: match conf.get_str("auth_server") {
-				Ok(s) => s,
-				Err(_) => panic!("Auth server is not a string"),
-			},
-			name: match conf.get_str("name") {
-				Ok(s) => s,
-				Err(_) => panic!("Name is not a string"),
-			},
-			description: match conf.get_str("description") {
-				Ok(s) => s,
-				Err(_) => panic!("Description is not a string"),
-			},
-			password: match conf.get_str("password") {
-				Ok(s) => s,
-				Err(_) => panic!("Password is not a string"),
-			},
-			version: match conf.get_str("version") {
-				Ok(s) => s,
-				Err(_) => panic!("Version is not a string"),
-			},
-			modinfo: match conf.get_str("modinfo") {
-				Ok(s) => s,
-				Err(_) => panic!("Modinfo is not a string"),
-			},
+			join_target,
+			join_policy,
+			join_weights: weights,
+			sticky_ttl,
+			auth_enabled: get_bool(&conf, &mut issues, "auth_enabled"),
+			auth_server: get_str(&conf, &mut issues, "auth_server"),
+			ban_file: get_str(&conf, &mut issues, "ban_file"),
+			event_log: get_str(&conf, &mut issues, "event_log"),
+			drain_timeout: get_uint(&conf, &mut issues, "drain_timeout"),
+			handshake_timeout,
+			idle_timeout,
+			backend_grace: get_uint(&conf, &mut issues, "backend_grace"),
+			cleanup_interval,
+			admin_token: get_str(&conf, &mut issues, "admin_token"),
+			name: get_str(&conf, &mut issues, "name"),
+			description: get_str(&conf, &mut issues, "description"),
+			password,
+			password_hash,
+			token_ttl,
+			max_tokens_per_user,
+			rate_limit_per_ip,
+			rate_limit_per_ip_burst,
+			rate_limit_global,
+			rate_limit_global_burst,
+			penalty_threshold: get_uint(&conf, &mut issues, "penalty_threshold") as u32,
+			penalty_duration: get_uint(&conf, &mut issues, "penalty_duration"),
+			connect_cookies: get_bool(&conf, &mut issues, "connect_cookies"),
+			cookie_lifetime,
+			password_max_failures: get_uint(&conf, &mut issues, "password_max_failures") as u32,
+			password_lockout: get_uint(&conf, &mut issues, "password_lockout"),
+			version: get_str(&conf, &mut issues, "version"),
+			modinfo: get_str(&conf, &mut issues, "modinfo"),
+			watch_config: get_bool(&conf, &mut issues, "watch_config"),
+		};
+		if issues.is_empty() {
+			Ok(config)
+		} else {
+			Err(ConfigError(issues))
+		}
+	}
+
+	/// Game port advertised to the master server
+	pub fn game_port(&self) -> u16 {
+		// Loading fails without at least one address
+		self.udp_addresses[0].port()
+	}
+
+	/// Whether joining players have to give a password
+	pub fn password_required(&self) -> bool {
+		!self.password.is_empty()
+	}
+
+	/// Compare a player's password with the configured one in constant time
+	pub fn password_matches(&self, given: &str) -> bool {
+		match &self.password_hash {
+			Some(hash) => Sha256::digest(given.as_bytes())
+				.as_slice()
+				.ct_eq(hash.as_slice())
+				.into(),
+			None => given.as_bytes().ct_eq(self.password.as_bytes()).into(),
+		}
+	}
+
+	/// Whether a user may join
+	/// Admins can always join
+	pub fn is_allowed(&self, user_id: u64) -> bool {
+		match &self.allowed_users {
+			Some(users) => users.contains(&user_id) || self.admins.contains(&user_id),
+			None => true,
+		}
+	}
+}
+
+#[cfg(test)]
+impl AppConfig {
+	/// Load a minimal config written by `ConfigSource::write_for_tests`
+	pub fn for_tests(extra: &str) -> AppConfig {
+		AppConfig::try_for_tests(extra).unwrap()
+	}
+
+	/// Like `for_tests` but hands back the problems with the config
+	pub fn try_for_tests(extra: &str) -> Result<AppConfig, ConfigError> {
+		let source = ConfigSource::for_tests();
+		source.write_for_tests(extra);
+		let conf = AppConfig::load(&source);
+		source.remove_for_tests();
+		conf
+	}
+}
+
+#[cfg(test)]
+impl ConfigSource {
+	/// A config file in the temp directory that no other test uses
+	pub fn for_tests() -> ConfigSource {
+		use std::{
+			env, process,
+			sync::atomic::{AtomicUsize, Ordering},
+		};
+
+		static NEXT: AtomicUsize = AtomicUsize::new(0);
+		let path = env::temp_dir().join(format!(
+			"titanfront-test-{}-{}.toml",
+			process::id(),
+			NEXT.fetch_add(1, Ordering::Relaxed)
+		));
+		ConfigSource {
+			path: Some(path.to_string_lossy().into_owned()),
+			overrides: Vec::new(),
+		}
+	}
+
+	/// Write a minimal config with `extra` appended
+	/// Keys in `extra` replace the defaults set here
+	pub fn write_for_tests(&self, extra: &str) {
+		let defaults = [
+			("key", "\"AAAAAAAAAAAAAAAAAAAAAA==\""),
+			("udp_address", "\"127.0.0.1:0\""),
+			("relay_address", "\"127.0.0.1:0\""),
+			("target_servers", "[\"127.0.0.1:37015\"]"),
+			("auth_enabled", "false"),
+			("auth_server", "\"http://127.0.0.2:8080\""),
+			("ban_file", "\"\""),
+			("rate_limit_per_ip", "0"),
+			("rate_limit_global", "0"),
+		];
+		let given: Vec<&str> = extra
+			.lines()
+			.filter_map(|l| l.split('=').next())
+			.map(str::trim)
+			.collect();
+		let mut file = String::new();
+		for (key, value) in defaults {
+			if !given.contains(&key) {
+				file.push_str(&format!("{} = {}\n", key, value));
+			}
+		}
+		file.push_str(extra);
+		fs::write(self.path.as_ref().unwrap(), file).unwrap();
+	}
+
+	pub fn remove_for_tests(&self) {
+		fs::remove_file(self.path.as_ref().unwrap()).unwrap();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// printf hunter2 | sha256sum
+	const HUNTER2: &str = "f52fbd32b2b3b86ff88ef6c490628285f482af15ddcb29541f94bcf526a3f6c7";
+
+	#[test]
+	fn plaintext_passwords() {
+		let conf = AppConfig::for_tests("password = \"hunter2\"\n");
+		assert!(conf.password_required());
+		assert!(conf.password_matches("hunter2"));
+		assert!(!conf.password_matches("hunter"));
+		assert!(!conf.password_matches(""));
+		let open = AppConfig::for_tests("");
+		assert!(!open.password_required());
+	}
+
+	#[test]
+	fn hashed_passwords() {
+		let conf = AppConfig::for_tests(&format!(
+			"password = \"hunter2\"\npassword_hash = \"{}\"\n",
+			HUNTER2
+		));
+		assert!(conf.password_matches("hunter2"));
+		assert!(!conf.password_matches("hunter3"));
+		// The digest itself is not the password
+		assert!(!conf.password_matches(HUNTER2));
+	}
+
+	#[test]
+	fn hashes_have_to_match_the_password() {
+		for extra in [
+			format!("password = \"hunter3\"\npassword_hash = \"{}\"\n", HUNTER2),
+			format!("password_hash = \"{}\"\n", HUNTER2),
+			String::from("password = \"hunter2\"\npassword_hash = \"f52f\"\n"),
+		] {
+			let err = AppConfig::try_for_tests(&extra).unwrap_err();
+			assert!(
+				err.0
+					.iter()
+					.any(|i| matches!(i, ConfigIssue::BadValue("password_hash", _))),
+				"{}",
+				err
+			);
 		}
 	}
 }
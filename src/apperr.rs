@@ -1,3 +1,5 @@
+use std::{fmt, net::SocketAddr};
+
 use thiserror::Error;
 
 // Error coercion macro from here:
@@ -24,4 +26,71 @@ pub enum TitanfrontError {
 	NMSResponse(reqwest::Error),
 	#[error("Issue receiving UDP packets: {0}")]
 	SwitchReceive(std::io::Error),
+	#[error("Issue sending UDP packets: {0}")]
+	RelaySend(std::io::Error),
+	#[error("Issue binding relay socket: {0}")]
+	RelayBind(std::io::Error),
+	#[error("No target server with index {0}")]
+	BadTarget(usize),
+	#[error("No target server with address {0}")]
+	UnknownTarget(String),
+	#[error("No connected player with ID {0}")]
+	PlayerNotFound(u64),
+	#[error("Target server {0} did not answer the connect")]
+	MigrationTimeout(SocketAddr),
+	#[error("No relay sockets are available")]
+	NoRelaySocket(),
+	#[error("Issue accessing ban file: {0}")]
+	BanFile(std::io::Error),
+	#[error("Ban file is not valid JSON: {0}")]
+	BanFormat(serde_json::Error),
+	#[error("A ban needs exactly one of user_id or network")]
+	BadBan(),
+	#[error("Issue writing event log: {0}")]
+	EventFile(std::io::Error),
+	#[error("Packet failed to decrypt")]
+	Decrypt(),
+	#[error("Packet is too short for a {0}")]
+	Truncated(&'static str),
+	#[error("Packet is not a {0}")]
+	WrongMessage(&'static str),
+}
+
+/// A single problem found while loading the config
+#[derive(Error, Debug)]
+pub enum ConfigIssue {
+	#[error("Could not read config: {0}")]
+	Source(config::ConfigError),
+	#[error("Could not read {0}: {1}")]
+	File(String, std::io::Error),
+	#[error("{0} is not set")]
+	Missing(&'static str),
+	#[error("Bad value for {0}: {1}")]
+	BadValue(&'static str, String),
+	#[error("Key decodes to {0} bytes but AES-128 needs 16")]
+	KeyLength(usize),
+	#[error("Admin {0} is listed more than once")]
+	DuplicateAdmin(u64),
+	#[error("Target server {0} does not resolve")]
+	Unresolved(String),
+	#[error("No target servers to proxy")]
+	NoTargets(),
+	#[error("Join target {0} is out of range for {1} target servers")]
+	JoinTargetRange(usize, usize),
+	#[error("{0} join weights given for {1} target servers")]
+	WeightCount(usize, usize),
+}
+
+/// Every problem found while loading the config
+#[derive(Error, Debug)]
+pub struct ConfigError(pub Vec<ConfigIssue>);
+
+impl fmt::Display for ConfigError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{} problem(s) with the config", self.0.len())?;
+		for issue in &self.0 {
+			write!(f, "\n  {}", issue)?;
+		}
+		Ok(())
+	}
 }
@@ -1,29 +1,53 @@
-use crate::{appconfig::AppConfig, apperr::TitanfrontError, router::Router, Err};
+use crate::{
+	appconfig::{AppConfig, SharedConfig},
+	apperr::TitanfrontError,
+	banlist::{self, unix_now, Ban},
+	conntable::PlayerInfo,
+	metrics,
+	reload::Reloader,
+	router::Router,
+	Err,
+};
 
 use {
 	actix_web::{
-		dev::Server,
-		get, post,
-		web::{Data, Query},
-		App, HttpResponse, HttpServer,
+		delete,
+		dev::{Server, ServerHandle},
+		get,
+		http::{header, StatusCode},
+		post,
+		web::{Data, Json, Path, Query},
+		App, HttpRequest, HttpResponse, HttpServer,
 	},
 	anyhow::Result,
+	dashmap::DashMap,
+	ipnet::IpNet,
 	reqwest::multipart::{Form, Part},
 	serde::{Deserialize, Serialize},
+	subtle::ConstantTimeEq,
 	tokio::{
+		select,
 		time::{sleep, Duration},
 		try_join,
 	},
 };
 
-use std::sync::{Arc, RwLock};
+use std::{
+	future::Future,
+	net::{SocketAddr, ToSocketAddrs},
+	sync::{Arc, RwLock},
+	time::Instant,
+};
 
 #[derive(Clone, Debug)]
 struct State {
 	router: Arc<Router>,
-	conf: Arc<AppConfig>,
+	conf: SharedConfig,
+	reloader: Arc<Reloader>,
 	server_auth: Arc<RwLock<String>>,
 	server_id: Arc<RwLock<String>>,
+	password_failures: Arc<PasswordFailures>,
+	started: Instant,
 }
 
 #[derive(Deserialize, Debug)]
@@ -62,7 +86,7 @@ pub struct AddRequest {
 	password: String,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 // These are northstar specified names provided as query parameters
 #[allow(non_snake_case)]
 // Some of these names are expected but currently unused
@@ -90,6 +114,23 @@ pub struct AddResponse {
 	error: Option<RequestError>,
 }
 
+/// Listing details sent to the master server after a reload
+#[derive(Serialize, Debug)]
+// These are northstar specified names provided as query parameters
+#[allow(non_snake_case)]
+pub struct UpdateRequest {
+	id: String,
+	port: u16,
+	authPort: u16,
+	name: String,
+	description: String,
+	map: String,
+	playlist: String,
+	playerCount: u64,
+	maxPlayers: u64,
+	password: String,
+}
+
 #[derive(Serialize, Debug)]
 // These are northstar specified names provided as query parameters
 #[allow(non_snake_case)]
@@ -102,6 +143,167 @@ pub struct Heartbeat {
 	id: String,
 }
 
+/// Failure body in the format NorthstarMasterServer uses
+#[derive(Serialize, Debug)]
+pub struct ErrorResponse {
+	success: bool,
+	error: RequestError,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MigrateRequest {
+	/// Index into the target server list
+	target: usize,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JoinTargetRequest {
+	/// Index into the target server list
+	index: Option<usize>,
+	/// Address of one of the target servers
+	address: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct JoinTargetResponse {
+	index: usize,
+	address: SocketAddr,
+}
+
+/// Admin view of a registry entry
+/// The auth token itself is left out on purpose
+#[derive(Serialize, Debug)]
+pub struct PlayerResponse {
+	user_id: u64,
+	username: String,
+	/// Whether the master server issued the player a token
+	token_issued: bool,
+	client: Option<SocketAddr>,
+	relay: Option<usize>,
+	target: Option<SocketAddr>,
+	/// Seconds since the connect handshake
+	connected_secs: Option<u64>,
+	/// Seconds since traffic was last relayed
+	idle_secs: u64,
+}
+
+impl From<PlayerInfo> for PlayerResponse {
+	fn from(p: PlayerInfo) -> Self {
+		PlayerResponse {
+			user_id: p.user_id,
+			username: p.username,
+			token_issued: !p.auth_token.is_empty(),
+			client: p.client,
+			relay: p.relay,
+			target: p.target,
+			connected_secs: p.connected.map(|c| c.elapsed().as_secs()),
+			idle_secs: p.last_seen.elapsed().as_secs(),
+		}
+	}
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BanRequest {
+	user_id: Option<u64>,
+	/// Address or CIDR range
+	#[serde(default, deserialize_with = "banlist::deserialize_network")]
+	network: Option<IpNet>,
+	reason: Option<String>,
+	/// Seconds until the ban lapses
+	/// Permanent when unset
+	duration: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct UnbanRequest {
+	user_id: Option<u64>,
+	#[serde(default, deserialize_with = "banlist::deserialize_network")]
+	network: Option<IpNet>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TargetStatus {
+	pub address: SocketAddr,
+	/// Live binds on this target
+	pub binds: usize,
+}
+
+/// Summary of a running instance for `titanfront status`
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatusResponse {
+	pub name: String,
+	pub uptime_secs: u64,
+	pub draining: bool,
+	/// Players that have completed the connect handshake
+	pub players: usize,
+	pub player_slots: usize,
+	pub free_sockets: usize,
+	pub join_target: usize,
+	pub targets: Vec<TargetStatus>,
+	/// Successful config reloads since startup
+	pub reloads: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct AdminResponse {
+	success: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error: Option<String>,
+}
+
+fn northstar_error(status: StatusCode, error_id: &str, msg: &str) -> HttpResponse {
+	HttpResponse::build(status)
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.json(ErrorResponse {
+			success: false,
+			error: RequestError {
+				error_id: error_id.to_owned(),
+				msg: msg.to_owned(),
+			},
+		})
+}
+
+/// Check the admin bearer token
+/// The admin API is disabled when no token is configured
+fn admin_authorized(req: &HttpRequest, conf: &AppConfig) -> bool {
+	if conf.admin_token.is_empty() {
+		return false;
+	}
+	match req
+		.headers()
+		.get(header::AUTHORIZATION)
+		.and_then(|h| h.to_str().ok())
+	{
+		// Compared in constant time so the token cannot be guessed byte by byte
+		Some(h) => h
+			.strip_prefix("Bearer ")
+			.is_some_and(|t| t.as_bytes().ct_eq(conf.admin_token.as_bytes()).into()),
+		None => false,
+	}
+}
+
+fn admin_reply(status: StatusCode, error: Option<String>) -> HttpResponse {
+	HttpResponse::build(status)
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.json(AdminResponse {
+			success: status.is_success(),
+			error,
+		})
+}
+
+fn admin_error(e: &anyhow::Error) -> HttpResponse {
+	let status = match e.downcast_ref::<TitanfrontError>() {
+		Some(TitanfrontError::PlayerNotFound(_)) => StatusCode::NOT_FOUND,
+		Some(TitanfrontError::BadTarget(_)) => StatusCode::BAD_REQUEST,
+		Some(TitanfrontError::UnknownTarget(_)) => StatusCode::BAD_REQUEST,
+		Some(TitanfrontError::NoRelaySocket()) => StatusCode::SERVICE_UNAVAILABLE,
+		Some(TitanfrontError::MigrationTimeout(_)) => StatusCode::GATEWAY_TIMEOUT,
+		Some(TitanfrontError::BadBan()) => StatusCode::BAD_REQUEST,
+		_ => StatusCode::INTERNAL_SERVER_ERROR,
+	};
+	admin_reply(status, Some(format!("{:#}", e)))
+}
+
 #[get("/verify")]
 async fn verify(_state: Data<State>) -> HttpResponse {
 	HttpResponse::Ok()
@@ -109,9 +311,83 @@ async fn verify(_state: Data<State>) -> HttpResponse {
 		.body("I am a northstar server!")
 }
 
+/// Outcome of checking a player's password
+#[derive(Debug, PartialEq, Eq)]
+enum PasswordCheck {
+	Accepted,
+	/// Failures in a row including this one
+	Wrong(u32),
+	/// Too many failures within the lockout
+	Throttled,
+}
+
+/// Wrong password count and time of the last failure per user
+#[derive(Debug, Default)]
+struct PasswordFailures(DashMap<u64, (u32, Instant)>);
+
+impl PasswordFailures {
+	fn check(&self, user_id: u64, given: &str, conf: &AppConfig) -> PasswordCheck {
+		let lockout = Duration::from_secs(conf.password_lockout);
+		if let Some(f) = self.0.get(&user_id) {
+			let (count, last) = *f.value();
+			if count >= conf.password_max_failures && last.elapsed() < lockout {
+				return PasswordCheck::Throttled;
+			}
+		}
+		if conf.password_matches(given) {
+			self.0.remove(&user_id);
+			return PasswordCheck::Accepted;
+		}
+		// Forget failures that are older than the lockout
+		self.0.retain(|_, (_, last)| last.elapsed() < lockout);
+		let mut entry = self.0.entry(user_id).or_insert((0, Instant::now()));
+		entry.0 += 1;
+		entry.1 = Instant::now();
+		PasswordCheck::Wrong(entry.0)
+	}
+}
+
+/// Returns the rejection to send if the player's password is wrong or they are throttled
+fn check_password(state: &State, con_req: &ConnectRequest) -> Option<HttpResponse> {
+	let given = con_req.password.as_deref().unwrap_or("");
+	match state
+		.password_failures
+		.check(con_req.id, given, &state.conf.load())
+	{
+		PasswordCheck::Accepted => None,
+		PasswordCheck::Throttled => {
+			log::warn!(
+				"Throttled password attempt from {}:{}",
+				con_req.id,
+				con_req.username
+			);
+			Some(northstar_error(
+				StatusCode::TOO_MANY_REQUESTS,
+				"UNAUTHORIZED_PWD",
+				"Too many failed password attempts",
+			))
+		}
+		PasswordCheck::Wrong(failures) => {
+			metrics::inc(&state.router.metrics().auth_failures);
+			log::warn!(
+				"Wrong password from {}:{} ({} failures)",
+				con_req.id,
+				con_req.username,
+				failures
+			);
+			Some(northstar_error(
+				StatusCode::FORBIDDEN,
+				"UNAUTHORIZED_PWD",
+				"Wrong password",
+			))
+		}
+	}
+}
+
+#[allow(clippy::needless_return)]
 #[post("/authenticate_incoming_player")]
 async fn auth_incoming_player(state: Data<State>, con_req: Query<ConnectRequest>) -> HttpResponse {
-	let conf = &state.conf;
+	let conf = state.conf.load_full();
 	// You can't seem to compare an RwGuard<String> with a String using !=
 	if state
 		.server_auth
@@ -124,9 +400,51 @@ async fn auth_incoming_player(state: Data<State>, con_req: Query<ConnectRequest>
 			.content_type("application/json")
 			.body("{\"success\":false}");
 	}
+	if state.router.is_draining() {
+		return northstar_error(
+			StatusCode::SERVICE_UNAVAILABLE,
+			"CONNECTION_REJECTED",
+			"Server is shutting down",
+		);
+	}
+	if let Some(ban) = state.router.bans().check(Some(con_req.id), None) {
+		log::warn!(
+			"Refused token for banned player {}:{}: {}",
+			con_req.id,
+			con_req.username,
+			ban.reason
+		);
+		return northstar_error(
+			StatusCode::FORBIDDEN,
+			"CONNECTION_REJECTED",
+			"You are banned from this server",
+		);
+	}
+	if !conf.is_allowed(con_req.id) {
+		log::warn!(
+			"Refused token for {}:{}. Not on the allow list",
+			con_req.id,
+			con_req.username
+		);
+		return northstar_error(
+			StatusCode::FORBIDDEN,
+			"CONNECTION_REJECTED",
+			"This server is private",
+		);
+	}
+	if conf.password_required() {
+		if let Some(resp) = check_password(&state, &con_req) {
+			return resp;
+		}
+	}
 	match state
 		.router
-		.add_token(con_req.authToken.clone(), con_req.id, conf)
+		.add_token(
+			con_req.authToken.clone(),
+			con_req.id,
+			con_req.username.clone(),
+			&conf,
+		)
 		.await
 	{
 		Ok(_) => {
@@ -146,9 +464,277 @@ async fn auth_incoming_player(state: Data<State>, con_req: Query<ConnectRequest>
 	}
 }
 
+#[post("/admin/players/{uid}/migrate")]
+async fn migrate_player(
+	state: Data<State>,
+	req: HttpRequest,
+	uid: Path<u64>,
+	mig: Query<MigrateRequest>,
+) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	match state
+		.router
+		.migrate_player(*uid, mig.target, &state.conf)
+		.await
+	{
+		Ok(_) => admin_reply(StatusCode::OK, None),
+		Err(e) => {
+			log::warn!("Could not migrate {}: {:#}", uid, e);
+			admin_error(&e)
+		}
+	}
+}
+
+#[get("/metrics")]
+async fn get_metrics(state: Data<State>) -> HttpResponse {
+	let conf = state.conf.load_full();
+	let loads: Vec<(SocketAddr, usize)> = conf
+		.target_servers
+		.iter()
+		.copied()
+		.zip(state.router.target_loads(&conf))
+		.collect();
+	let body = state.router.metrics().render(
+		state.router.free_sockets(),
+		state.router.penalized(),
+		&loads,
+	);
+	HttpResponse::Ok()
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.content_type("text/plain; version=0.0.4")
+		.body(body)
+}
+
+#[get("/admin/players")]
+async fn list_players(state: Data<State>, req: HttpRequest) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	let players: Vec<PlayerResponse> = state
+		.router
+		.list_players()
+		.into_iter()
+		.map(PlayerResponse::from)
+		.collect();
+	HttpResponse::Ok()
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.json(players)
+}
+
+#[get("/admin/players/{uid}")]
+async fn get_player(state: Data<State>, req: HttpRequest, uid: Path<u64>) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	match state.router.get_player(*uid) {
+		Some(p) => HttpResponse::Ok()
+			.insert_header(("X-Forwarded-By", "Titanfront"))
+			.json(PlayerResponse::from(p)),
+		None => admin_error(&anyhow::anyhow!(TitanfrontError::PlayerNotFound(*uid))),
+	}
+}
+
+#[delete("/admin/players/{uid}")]
+async fn kick_player(state: Data<State>, req: HttpRequest, uid: Path<u64>) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	match state.router.kick_player(*uid, "Kicked by an admin").await {
+		Ok(_) => admin_reply(StatusCode::OK, None),
+		Err(e) => admin_error(&e),
+	}
+}
+
+#[get("/admin/bans")]
+async fn list_bans(state: Data<State>, req: HttpRequest) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	HttpResponse::Ok()
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.json(state.router.bans().list())
+}
+
+#[post("/admin/bans")]
+async fn add_ban(state: Data<State>, req: HttpRequest, ban_req: Json<BanRequest>) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	let ban_req = ban_req.into_inner();
+	let ban = Ban {
+		user_id: ban_req.user_id,
+		network: ban_req.network,
+		reason: ban_req.reason.unwrap_or_default(),
+		expires: ban_req.duration.map(|d| unix_now() + d),
+	};
+	log::info!("Adding ban {:?}", ban);
+	match state.router.add_ban(ban).await {
+		Ok(_) => admin_reply(StatusCode::OK, None),
+		Err(e) => admin_error(&e),
+	}
+}
+
+#[delete("/admin/bans")]
+async fn remove_ban(
+	state: Data<State>,
+	req: HttpRequest,
+	unban: Query<UnbanRequest>,
+) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	match state.router.bans().remove(unban.user_id, unban.network) {
+		Ok(true) => admin_reply(StatusCode::OK, None),
+		Ok(false) => admin_reply(StatusCode::NOT_FOUND, Some(String::from("No such ban"))),
+		Err(e) => admin_error(&e),
+	}
+}
+
+#[get("/admin/join_target")]
+async fn get_join_target(state: Data<State>, req: HttpRequest) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	let index = state.router.get_join_target();
+	// Only out of range for the moment a reload takes to apply
+	let address = match state.conf.load().target_servers.get(index) {
+		Some(a) => *a,
+		None => {
+			return admin_reply(
+				StatusCode::SERVICE_UNAVAILABLE,
+				Some(TitanfrontError::BadTarget(index).to_string()),
+			)
+		}
+	};
+	HttpResponse::Ok()
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.json(JoinTargetResponse { index, address })
+}
+
+#[post("/admin/join_target")]
+async fn set_join_target(
+	state: Data<State>,
+	req: HttpRequest,
+	join: Query<JoinTargetRequest>,
+) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	let conf = state.conf.load_full();
+	let index = match (join.index, &join.address) {
+		(Some(i), None) => i,
+		(None, Some(a)) => {
+			let addr = match a.parse::<SocketAddr>() {
+				Ok(addr) => Some(addr),
+				Err(_) => a.to_socket_addrs().ok().and_then(|mut itr| itr.next()),
+			};
+			match addr.and_then(|addr| conf.target_servers.iter().position(|t| *t == addr)) {
+				Some(i) => i,
+				None => {
+					return admin_error(&anyhow::anyhow!(TitanfrontError::UnknownTarget(a.clone())))
+				}
+			}
+		}
+		_ => {
+			return admin_reply(
+				StatusCode::BAD_REQUEST,
+				Some(String::from("Specify exactly one of index or address")),
+			)
+		}
+	};
+	match state.router.set_join_target(index, &conf) {
+		Ok(_) => admin_reply(StatusCode::OK, None),
+		Err(e) => admin_error(&e),
+	}
+}
+
+#[get("/admin/status")]
+async fn get_status(state: Data<State>, req: HttpRequest) -> HttpResponse {
+	let conf = state.conf.load_full();
+	if !admin_authorized(&req, &conf) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	let targets = conf
+		.target_servers
+		.iter()
+		.zip(state.router.target_loads(&conf))
+		.map(|(address, binds)| TargetStatus {
+			address: *address,
+			binds,
+		})
+		.collect();
+	HttpResponse::Ok()
+		.insert_header(("X-Forwarded-By", "Titanfront"))
+		.json(StatusResponse {
+			name: conf.name.clone(),
+			uptime_secs: state.started.elapsed().as_secs(),
+			draining: state.router.is_draining(),
+			players: state.router.authenticated_count(),
+			player_slots: conf.player_count,
+			free_sockets: state.router.free_sockets(),
+			join_target: state.router.get_join_target(),
+			targets,
+			reloads: state.reloader.generation(),
+		})
+}
+
+#[post("/admin/reload")]
+async fn reload_config(state: Data<State>, req: HttpRequest) -> HttpResponse {
+	if !admin_authorized(&req, &state.conf.load()) {
+		return admin_reply(StatusCode::UNAUTHORIZED, None);
+	}
+	match state.reloader.reload().await {
+		Ok(_) => admin_reply(StatusCode::OK, None),
+		Err(e) => {
+			log::error!("{:#}", e);
+			admin_error(&e)
+		}
+	}
+}
+
+/// Push the current name, description, password and modinfo to the master server
+async fn update_server(state: &State) -> Result<()> {
+	let conf = state.conf.load_full();
+	let update_req = UpdateRequest {
+		id: state.server_id.read().unwrap().to_string(),
+		port: conf.game_port(),
+		authPort: conf.auth_address.port(),
+		name: conf.name.clone(),
+		description: conf.description.clone(),
+		map: String::from("????"),
+		playlist: String::from("????"),
+		playerCount: state.router.get_player_count(),
+		maxPlayers: conf.player_count as u64,
+		password: conf.password.clone(),
+	};
+	let client = reqwest::Client::new();
+	let part = Part::text(conf.modinfo.clone())
+		.file_name("modinfo.json")
+		.mime_str("application/json")?;
+	let form = Form::new().part("modinfo", part);
+	match client
+		.post(format!("{}/server/update_values", conf.auth_server))
+		.header("User-Agent", format!("R2Northstar/{}", conf.version))
+		.query(&update_req)
+		.multipart(form)
+		.header("Content-Type", "text/plain")
+		.send()
+		.await
+		.and_then(|r| r.error_for_status())
+	{
+		Ok(_) => Ok(()),
+		Err(e) => Err!(TitanfrontError::NMSResponse(e)),
+	}
+}
+
 async fn publish_server(state: &State) -> Result<()> {
 	sleep(Duration::from_secs(1)).await;
-	let conf = &state.conf;
+	// Settings that cannot be reloaded are read from here
+	// Anything that can change is pushed with update_server instead
+	let conf = state.conf.load_full();
+	let mut published = state.reloader.generation();
 	match reqwest::get(format!("http://{}/verify", conf.auth_address))
 		.await?
 		.text()
@@ -168,7 +754,7 @@ async fn publish_server(state: &State) -> Result<()> {
 	log::info!("authserver completed startup");
 	if conf.auth_enabled {
 		let add_req = AddRequest {
-			port: conf.udp_address.port(),
+			port: conf.game_port(),
 			authPort: conf.auth_address.port(),
 			name: conf.name.clone(),
 			description: conf.description.clone(),
@@ -211,14 +797,30 @@ async fn publish_server(state: &State) -> Result<()> {
 			}
 		}
 		log::debug!("SERVER ID VAL 1:{:?}", state.server_id.read().unwrap());
+		let mut failures = 0u64;
 		loop {
 			sleep(Duration::from_secs(5)).await;
+			// A heartbeat after deregistering would relist the server
+			if state.router.is_draining() {
+				return Ok(());
+			}
+			let generation = state.reloader.generation();
+			if generation != published {
+				match update_server(state).await {
+					Ok(_) => {
+						log::info!("Pushed reloaded config to NorthstarMasterServer");
+						published = generation;
+					}
+					// Retried with the next heartbeat
+					Err(e) => log::error!("Could not update master server listing: {:#}", e),
+				}
+			}
 			let heartbeat = Heartbeat {
 				playerCount: state.router.get_player_count(),
 				id: state.server_id.read().unwrap().to_string(),
 			};
 			let client = reqwest::Client::new();
-			let part = Part::text(conf.modinfo.clone())
+			let part = Part::text(state.conf.load().modinfo.clone())
 				.file_name("modinfo.json")
 				.mime_str("application/json")?;
 			let form = Form::new().part("modinfo", part);
@@ -229,13 +831,26 @@ async fn publish_server(state: &State) -> Result<()> {
 				.multipart(form)
 				.header("Content-Type", "text/plain")
 				.send()
-				.await?
-				.text();
-			match heartbeat_req.await {
-				Ok(_r) => {}
+				.await;
+			// Count failures to send as well as error statuses and bad responses
+			let heartbeat_resp = match heartbeat_req.and_then(|r| r.error_for_status()) {
+				Ok(r) => r.text().await,
+				Err(e) => Err(e),
+			};
+			match heartbeat_resp {
+				Ok(_r) => {
+					metrics::inc(&state.router.metrics().heartbeat_successes);
+					failures = 0;
+				}
+				// Retried with the next heartbeat so a bad reply does not end the match
 				Err(e) => {
-					log::error!("NorthstarMasterServer issued bad response to heartbeat");
-					return Err!(TitanfrontError::NMSResponse(e));
+					metrics::inc(&state.router.metrics().heartbeat_failures);
+					failures += 1;
+					log::error!(
+						"NorthstarMasterServer issued bad response to heartbeat ({} in a row): {}",
+						failures,
+						e
+					);
 				}
 			}
 		}
@@ -244,36 +859,196 @@ async fn publish_server(state: &State) -> Result<()> {
 	}
 }
 
+/// Remove this server from the master server's list
+async fn remove_server(state: &State) -> Result<()> {
+	let conf = state.conf.load_full();
+	let id = state.server_id.read().unwrap().to_string();
+	if id.is_empty() {
+		return Ok(());
+	}
+	let client = reqwest::Client::new();
+	match client
+		.delete(format!("{}/server/remove_server", conf.auth_server))
+		.header("User-Agent", format!("R2Northstar/{}", conf.version))
+		.query(&[("id", id)])
+		.send()
+		.await
+	{
+		Ok(_) => Ok(()),
+		Err(e) => Err!(TitanfrontError::NMSResponse(e)),
+	}
+}
+
+/// Wait for the shutdown signal then drain connected players
+/// New players are refused immediately. Existing binds are relayed until they go idle or the drain timeout passes.
+async fn drain(
+	shutdown: impl Future<Output = ()>,
+	state: &State,
+	server: ServerHandle,
+) -> Result<()> {
+	shutdown.await;
+	let conf = state.conf.load_full();
+	log::info!("Shutting down. Refusing new players");
+	state.router.start_draining();
+	if conf.auth_enabled {
+		match remove_server(state).await {
+			Ok(_) => log::info!("Removed server from NorthstarMasterServer"),
+			Err(e) => log::error!("Could not remove server from master server: {:#}", e),
+		}
+	}
+	let deadline = Instant::now() + Duration::from_secs(conf.drain_timeout);
+	// Idle binds are reclaimed by the cleanup task
+	while state.router.authenticated_count() > 0 && Instant::now() < deadline {
+		sleep(Duration::from_secs(1)).await;
+	}
+	let remaining = state.router.authenticated_count();
+	if remaining > 0 {
+		log::warn!("Drain timed out with {} players connected", remaining);
+	} else {
+		log::info!("All players have left");
+	}
+	server.stop(true).await;
+	Ok(())
+}
+
 async fn server_caller(server: Server) -> Result<()> {
 	server.await.or_else(|err| Err!(err))
 }
 
-pub async fn build_and_run(router: Arc<Router>, conf: Arc<AppConfig>) -> Result<()> {
+pub async fn build_and_run(
+	router: Arc<Router>,
+	conf: SharedConfig,
+	reloader: Arc<Reloader>,
+	shutdown: impl Future<Output = ()>,
+) -> Result<()> {
 	// Setup authserver
 	log::info!("Setting up auth server");
+	let auth_address = conf.load().auth_address;
 	let state = State {
 		router,
-		conf: conf.clone(),
+		conf,
+		reloader,
 		server_id: Arc::new(RwLock::new(String::new())),
 		server_auth: Arc::new(RwLock::new(String::new())),
+		password_failures: Arc::new(PasswordFailures::default()),
+		started: Instant::now(),
 	};
 	let authsv_state = state.clone();
 	let authserver = HttpServer::new(move || {
 		App::new()
 			.app_data(Data::new(authsv_state.clone()))
 			.service(verify)
+			.service(get_metrics)
 			.service(auth_incoming_player)
+			.service(list_players)
+			.service(get_player)
+			.service(kick_player)
+			.service(migrate_player)
+			.service(list_bans)
+			.service(add_ban)
+			.service(remove_ban)
+			.service(get_join_target)
+			.service(set_join_target)
+			.service(reload_config)
+			.service(get_status)
 	})
-	.bind(conf.auth_address)?
+	.bind(auth_address)?
+	// Signals are handled by drain so players are not dropped
+	.disable_signals()
 	.run();
 
+	let handle = authserver.handle();
 	let serv_caller = server_caller(authserver);
 	let publish = publish_server(&state);
 
 	log::info!("Starting auth server");
 
-	match try_join!(publish, serv_caller) {
-		Ok(_) => Ok(()),
-		Err(e) => Err(e),
+	select! {
+		res = async { try_join!(publish, serv_caller) } => match res {
+			Ok(_) => Ok(()),
+			Err(e) => Err(e),
+		},
+		res = drain(shutdown, &state, handle) => res,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn admin_tokens() {
+		let conf = AppConfig::for_tests("admin_token = \"secret\"\n");
+		let request = |auth: Option<&str>| {
+			let req = actix_web::test::TestRequest::default();
+			match auth {
+				Some(a) => req.insert_header((header::AUTHORIZATION, a)),
+				None => req,
+			}
+			.to_http_request()
+		};
+		assert!(admin_authorized(&request(Some("Bearer secret")), &conf));
+		for auth in [
+			Some("Bearer secre"),
+			Some("Bearer secrets"),
+			Some("Bearer "),
+			Some("secret"),
+			None,
+		] {
+			assert!(!admin_authorized(&request(auth), &conf), "{:?}", auth);
+		}
+		// No token disables the admin API
+		let open = AppConfig::for_tests("");
+		assert!(!admin_authorized(&request(Some("Bearer ")), &open));
+	}
+
+	#[test]
+	fn wrong_passwords_are_throttled() {
+		let conf = AppConfig::for_tests(
+			"password = \"hunter2\"\n\
+			password_max_failures = 2\n\
+			password_lockout = 60\n",
+		);
+		let failures = PasswordFailures::default();
+		assert_eq!(failures.check(1, "hunter2", &conf), PasswordCheck::Accepted);
+		assert_eq!(failures.check(1, "guess", &conf), PasswordCheck::Wrong(1));
+		assert_eq!(failures.check(1, "guess", &conf), PasswordCheck::Wrong(2));
+		// Locked out even with the right password
+		assert_eq!(
+			failures.check(1, "hunter2", &conf),
+			PasswordCheck::Throttled
+		);
+		// Other players are not affected
+		assert_eq!(failures.check(2, "hunter2", &conf), PasswordCheck::Accepted);
+	}
+
+	#[test]
+	fn lockouts_lapse() {
+		let conf = AppConfig::for_tests(
+			"password = \"hunter2\"\n\
+			password_max_failures = 2\n\
+			password_lockout = 60\n",
+		);
+		let failures = PasswordFailures::default();
+		let long_ago = Instant::now() - Duration::from_secs(61);
+		failures.0.insert(1, (2, long_ago));
+		failures.0.insert(2, (2, long_ago));
+		// A lapsed lockout starts counting again
+		assert_eq!(failures.check(1, "guess", &conf), PasswordCheck::Wrong(1));
+		// The right password clears the count
+		assert_eq!(failures.check(2, "hunter2", &conf), PasswordCheck::Accepted);
+		assert!(failures.0.get(&2).is_none());
+	}
+
+	#[test]
+	fn correct_passwords_reset_the_count() {
+		let conf = AppConfig::for_tests(
+			"password = \"hunter2\"\n\
+			password_max_failures = 2\n",
+		);
+		let failures = PasswordFailures::default();
+		assert_eq!(failures.check(1, "guess", &conf), PasswordCheck::Wrong(1));
+		assert_eq!(failures.check(1, "hunter2", &conf), PasswordCheck::Accepted);
+		assert_eq!(failures.check(1, "guess", &conf), PasswordCheck::Wrong(1));
 	}
 }
@@ -0,0 +1,227 @@
+use crate::{apperr::TitanfrontError, Err};
+
+use std::{
+	fs,
+	io::ErrorKind,
+	net::IpAddr,
+	path::PathBuf,
+	sync::{Mutex, RwLock},
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use {
+	anyhow::{Context, Result},
+	ipnet::IpNet,
+	serde::{de, Deserialize, Deserializer, Serialize},
+};
+
+/// A single ban
+/// Exactly one of `user_id` or `network` is set
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Ban {
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub user_id: Option<u64>,
+	/// Single addresses are stored as /32 or /128 ranges
+	#[serde(
+		default,
+		deserialize_with = "deserialize_network",
+		skip_serializing_if = "Option::is_none"
+	)]
+	pub network: Option<IpNet>,
+	#[serde(default)]
+	pub reason: String,
+	/// Unix time the ban lapses
+	/// Permanent when unset
+	#[serde(default)]
+	pub expires: Option<u64>,
+}
+
+impl Ban {
+	fn is_active(&self, now: u64) -> bool {
+		self.expires.is_none_or(|e| e > now)
+	}
+
+	pub fn matches(&self, user_id: Option<u64>, ip: Option<IpAddr>) -> bool {
+		match (self.user_id, self.network) {
+			(Some(u), _) => user_id == Some(u),
+			(_, Some(n)) => ip.is_some_and(|i| n.contains(&i)),
+			_ => false,
+		}
+	}
+
+	fn same_subject(&self, other: &Ban) -> bool {
+		self.user_id == other.user_id && self.network == other.network
+	}
+}
+
+/// Parse an address or CIDR range
+/// A plain address becomes a host prefix covering just that address
+fn parse_network(s: &str) -> Option<IpNet> {
+	s.parse::<IpNet>()
+		.ok()
+		.or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Deserialize an optional address or CIDR range
+pub fn deserialize_network<'de, D: Deserializer<'de>>(
+	d: D,
+) -> std::result::Result<Option<IpNet>, D::Error> {
+	match Option::<String>::deserialize(d)? {
+		Some(s) => parse_network(&s)
+			.map(Some)
+			.ok_or_else(|| de::Error::custom(format!("{} is not an address or CIDR range", s))),
+		None => Ok(None),
+	}
+}
+
+/// Bans enforced by the proxy
+/// Persisted as JSON so they survive restarts
+#[derive(Debug)]
+pub struct BanList {
+	/// Bans are only kept in memory when unset
+	path: Option<PathBuf>,
+	bans: RwLock<Vec<Ban>>,
+	/// Held while writing the file so saves land in order
+	saving: Mutex<()>,
+}
+
+pub fn unix_now() -> u64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |d| d.as_secs())
+}
+
+impl BanList {
+	pub fn load(path: &str) -> Result<BanList> {
+		if path.is_empty() {
+			log::warn!("No ban file configured. Bans will not be persisted");
+			return Ok(BanList {
+				path: None,
+				bans: RwLock::new(Vec::new()),
+				saving: Mutex::new(()),
+			});
+		}
+		let bans = match fs::read(path) {
+			Ok(b) => match serde_json::from_slice::<Vec<Ban>>(&b) {
+				Ok(bans) => bans,
+				Err(e) => {
+					return Err!(TitanfrontError::BanFormat(e))
+						.with_context(|| format!("Error parsing ban file {}", path))
+				}
+			},
+			// First run
+			Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+			Err(e) => {
+				return Err!(TitanfrontError::BanFile(e))
+					.with_context(|| format!("Error reading ban file {}", path))
+			}
+		};
+		log::info!("Loaded {} bans from {}", bans.len(), path);
+		Ok(BanList {
+			path: Some(PathBuf::from(path)),
+			bans: RwLock::new(bans),
+			saving: Mutex::new(()),
+		})
+	}
+
+	/// Write the current bans to the file
+	/// The list is copied first so checks are not held up by the disk
+	fn save(&self) -> Result<()> {
+		let path = match &self.path {
+			Some(p) => p,
+			None => return Ok(()),
+		};
+		// Copied after taking the save lock so the last save always writes the latest list
+		let _saving = self.saving.lock().unwrap();
+		let bans = self.bans.read().unwrap().clone();
+		// Write then rename so a crash never leaves a truncated file
+		let tmp = path.with_extension("tmp");
+		let json = serde_json::to_vec_pretty(&bans).map_err(TitanfrontError::BanFormat)?;
+		fs::write(&tmp, json)
+			.and_then(|_| fs::rename(&tmp, path))
+			.map_err(TitanfrontError::BanFile)
+			.context("Error writing ban file")
+	}
+
+	/// First active ban matching either the user or the address
+	pub fn check(&self, user_id: Option<u64>, ip: Option<IpAddr>) -> Option<Ban> {
+		let now = unix_now();
+		self.bans
+			.read()
+			.unwrap()
+			.iter()
+			.find(|b| b.is_active(now) && b.matches(user_id, ip))
+			.cloned()
+	}
+
+	/// Active bans
+	pub fn list(&self) -> Vec<Ban> {
+		let now = unix_now();
+		self.bans
+			.read()
+			.unwrap()
+			.iter()
+			.filter(|b| b.is_active(now))
+			.cloned()
+			.collect()
+	}
+
+	/// Add a ban, replacing any existing ban on the same user or network
+	pub fn add(&self, ban: Ban) -> Result<()> {
+		if ban.user_id.is_some() == ban.network.is_some() {
+			return Err!(TitanfrontError::BadBan());
+		}
+		{
+			let mut bans = self.bans.write().unwrap();
+			bans.retain(|b| !b.same_subject(&ban));
+			bans.push(ban);
+		}
+		self.save()
+	}
+
+	/// Returns whether a ban was removed
+	pub fn remove(&self, user_id: Option<u64>, network: Option<IpNet>) -> Result<bool> {
+		{
+			let mut bans = self.bans.write().unwrap();
+			let before = bans.len();
+			bans.retain(|b| !(b.user_id == user_id && b.network == network));
+			if bans.len() == before {
+				return Ok(false);
+			}
+		}
+		self.save()?;
+		Ok(true)
+	}
+
+	/// Drop lapsed bans so the file does not grow forever
+	pub fn purge_expired(&self) -> Result<()> {
+		let now = unix_now();
+		{
+			let mut bans = self.bans.write().unwrap();
+			let before = bans.len();
+			bans.retain(|b| b.is_active(now));
+			if bans.len() == before {
+				return Ok(());
+			}
+		}
+		self.save()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn plain_addresses_ban_one_host() {
+		let ban: Ban = serde_json::from_str(r#"{"network": "10.0.0.5"}"#).unwrap();
+		assert_eq!(ban.network, "10.0.0.5/32".parse().ok());
+		assert!(ban.matches(None, "10.0.0.5".parse().ok()));
+		assert!(!ban.matches(None, "10.0.0.6".parse().ok()));
+		let ban: Ban = serde_json::from_str(r#"{"network": "2001:db8::1"}"#).unwrap();
+		assert_eq!(ban.network, "2001:db8::1/128".parse().ok());
+		let ban: Ban = serde_json::from_str(r#"{"network": "10.0.0.0/8"}"#).unwrap();
+		assert!(ban.matches(None, "10.1.2.3".parse().ok()));
+		assert!(serde_json::from_str::<Ban>(r#"{"network": "10.0.0"}"#).is_err());
+	}
+}
@@ -0,0 +1,282 @@
+use crate::{
+	appconfig::{AppConfig, ConfigSource},
+	authserver::StatusResponse,
+};
+
+use std::process;
+
+use {
+	anyhow::{Context, Result},
+	clap::{Args, Parser, Subcommand},
+	rand::{thread_rng, Rng},
+};
+
+/// Northstar server proxy
+#[derive(Parser, Debug)]
+#[command(version)]
+pub struct Cli {
+	#[command(subcommand)]
+	pub command: Option<Command>,
+	/// Same as the check-config subcommand
+	#[arg(long, hide = true)]
+	pub check_config: bool,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+	/// Start the proxy (the default when no subcommand is given)
+	Run(ConfigArgs),
+	/// Print every problem with the config and exit
+	CheckConfig(ConfigArgs),
+	/// Print a random server encryption key for `key`
+	GenKey,
+	/// Query a running instance through its admin API
+	Status(StatusArgs),
+}
+
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+	/// Config file to read instead of Titanfront.* in the working directory
+	#[arg(long, short)]
+	pub config: Option<String>,
+	#[command(flatten)]
+	pub overrides: Overrides,
+}
+
+/// Values that take precedence over the config file and environment
+#[derive(Args, Debug, Default)]
+pub struct Overrides {
+	/// UDP interface and port players connect to. Can be repeated
+	#[arg(long = "udp-address")]
+	udp_addresses: Vec<String>,
+	/// HTTP interface and port for the auth server
+	#[arg(long)]
+	auth_address: Option<String>,
+	/// Address relay sockets bind to
+	#[arg(long)]
+	relay_address: Option<String>,
+	/// Player slots excluding admins
+	#[arg(long)]
+	player_count: Option<u64>,
+	/// Size of the UDP receive buffer
+	#[arg(long)]
+	receive_buf_size: Option<u64>,
+	/// Datagrams read per system call. Only used on Linux
+	#[arg(long)]
+	udp_batch_size: Option<u64>,
+	/// Admin user ID. Can be repeated
+	#[arg(long = "admin")]
+	admins: Vec<u64>,
+	/// Target server address. Can be repeated
+	#[arg(long = "target-server")]
+	target_servers: Vec<String>,
+	/// Index of the target server new players join
+	#[arg(long)]
+	join_target: Option<u64>,
+	/// fixed, round_robin, least_connected, weighted or sticky
+	#[arg(long)]
+	join_policy: Option<String>,
+	/// Whether to use central authentication
+	#[arg(long)]
+	auth_enabled: Option<bool>,
+	/// URL of the master server
+	#[arg(long)]
+	auth_server: Option<String>,
+	/// JSON file bans are stored in
+	#[arg(long)]
+	ban_file: Option<String>,
+	/// File connection events are written to. `-` is stdout
+	#[arg(long)]
+	event_log: Option<String>,
+	/// Seconds to keep relaying after SIGTERM
+	#[arg(long)]
+	drain_timeout: Option<u64>,
+	/// Seconds a connect handshake may take
+	#[arg(long)]
+	handshake_timeout: Option<u64>,
+	/// Seconds a connected player may be silent before it is dropped
+	#[arg(long)]
+	idle_timeout: Option<u64>,
+	/// Extra idle seconds when the target server went quiet first
+	#[arg(long)]
+	backend_grace: Option<u64>,
+	/// Seconds between idle connection sweeps
+	#[arg(long)]
+	cleanup_interval: Option<u64>,
+	/// Bearer token for the admin API
+	#[arg(long)]
+	admin_token: Option<String>,
+	/// Server name shown in the server browser
+	#[arg(long)]
+	name: Option<String>,
+	/// Server description shown in the server browser
+	#[arg(long)]
+	description: Option<String>,
+	/// Password players need to join
+	#[arg(long)]
+	password: Option<String>,
+	/// Reload when the config file changes
+	#[arg(long)]
+	watch_config: Option<bool>,
+	/// Challenge new players with a connect cookie before giving them a relay
+	#[arg(long)]
+	connect_cookies: Option<bool>,
+}
+
+#[derive(Args, Debug)]
+pub struct StatusArgs {
+	/// Admin API base URL
+	/// Derived from `auth_address` in the config when unset
+	#[arg(long)]
+	url: Option<String>,
+	/// Admin API token
+	/// Read from `admin_token` in the config when unset
+	#[arg(long, env = "TITANFRONT_ADMIN_TOKEN", hide_env_values = true)]
+	token: Option<String>,
+	#[command(flatten)]
+	config: ConfigArgs,
+}
+
+impl Overrides {
+	fn values(&self) -> Vec<(&'static str, config::Value)> {
+		let mut values: Vec<(&'static str, config::Value)> = Vec::new();
+		let strings = [
+			("auth_address", &self.auth_address),
+			("relay_address", &self.relay_address),
+			("join_policy", &self.join_policy),
+			("auth_server", &self.auth_server),
+			("ban_file", &self.ban_file),
+			("event_log", &self.event_log),
+			("admin_token", &self.admin_token),
+			("name", &self.name),
+			("description", &self.description),
+			("password", &self.password),
+		];
+		for (key, value) in strings {
+			if let Some(v) = value {
+				values.push((key, v.clone().into()));
+			}
+		}
+		let ints = [
+			("player_count", self.player_count),
+			("receive_buf_size", self.receive_buf_size),
+			("udp_batch_size", self.udp_batch_size),
+			("join_target", self.join_target),
+			("drain_timeout", self.drain_timeout),
+			("handshake_timeout", self.handshake_timeout),
+			("idle_timeout", self.idle_timeout),
+			("backend_grace", self.backend_grace),
+			("cleanup_interval", self.cleanup_interval),
+		];
+		for (key, value) in ints {
+			if let Some(v) = value {
+				values.push((key, (v as i64).into()));
+			}
+		}
+		let bools = [
+			("auth_enabled", self.auth_enabled),
+			("watch_config", self.watch_config),
+			("connect_cookies", self.connect_cookies),
+		];
+		for (key, value) in bools {
+			if let Some(v) = value {
+				values.push((key, v.into()));
+			}
+		}
+		// Admin IDs are parsed from strings like they are in the config file
+		if !self.admins.is_empty() {
+			let admins: Vec<String> = self.admins.iter().map(|a| a.to_string()).collect();
+			values.push(("admins", admins.into()));
+		}
+		if !self.udp_addresses.is_empty() {
+			values.push(("udp_addresses", self.udp_addresses.clone().into()));
+		}
+		if !self.target_servers.is_empty() {
+			values.push(("target_servers", self.target_servers.clone().into()));
+		}
+		values
+	}
+}
+
+impl ConfigArgs {
+	pub fn source(&self) -> ConfigSource {
+		ConfigSource {
+			path: self.config.clone(),
+			overrides: self.overrides.values(),
+		}
+	}
+}
+
+/// Print every problem with the config and exit
+pub fn check_config(source: &ConfigSource) -> ! {
+	match AppConfig::load(source) {
+		Ok(conf) => {
+			println!(
+				"Config OK: {} target servers, {} player slots, {} admins",
+				conf.target_servers.len(),
+				conf.player_count,
+				conf.admins.len()
+			);
+			process::exit(0);
+		}
+		Err(e) => {
+			println!("{}", e);
+			process::exit(1);
+		}
+	}
+}
+
+pub fn gen_key() {
+	let key = thread_rng().gen::<[u8; 16]>();
+	println!("{}", base64::encode(key));
+}
+
+pub async fn status(args: &StatusArgs) -> Result<()> {
+	let (url, token) = match (&args.url, &args.token) {
+		(Some(url), Some(token)) => (url.clone(), token.clone()),
+		_ => {
+			let conf = AppConfig::load(&args.config.source())?;
+			let mut addr = conf.auth_address;
+			// Wildcard addresses cannot be connected to
+			if addr.ip().is_unspecified() {
+				addr.set_ip([127, 0, 0, 1].into());
+			}
+			(
+				args.url
+					.clone()
+					.unwrap_or_else(|| format!("http://{}", addr)),
+				args.token.clone().unwrap_or(conf.admin_token),
+			)
+		}
+	};
+	let status = reqwest::Client::new()
+		.get(format!("{}/admin/status", url.trim_end_matches('/')))
+		.bearer_auth(token)
+		.send()
+		.await
+		.and_then(|r| r.error_for_status())
+		.with_context(|| format!("Could not query {}", url))?
+		.json::<StatusResponse>()
+		.await
+		.context("Bad status response")?;
+
+	let state = if status.draining {
+		"draining"
+	} else {
+		"running"
+	};
+	println!("{} ({})", status.name, state);
+	println!("uptime        {}s", status.uptime_secs);
+	println!("players       {}/{}", status.players, status.player_slots);
+	println!("free sockets  {}", status.free_sockets);
+	println!("reloads       {}", status.reloads);
+	for (i, target) in status.targets.iter().enumerate() {
+		// The join target is starred
+		let marker = if i == status.join_target { "*" } else { " " };
+		println!(
+			"target {}{}     {} ({} binds)",
+			i, marker, target.address, target.binds
+		);
+	}
+	Ok(())
+}
@@ -0,0 +1,515 @@
+use crate::{pool::Lease, tsock::TUdpSocket};
+
+use std::{
+	net::SocketAddr,
+	sync::{
+		atomic::{AtomicU64, AtomicU8, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+
+const CONNECTING: u8 = 0;
+const AUTHENTICATED: u8 = 1;
+const CLOSED: u8 = 2;
+
+/// Where a connection's traffic goes
+/// Swapped out when the player is migrated
+#[derive(Debug)]
+struct Route {
+	/// Relay socket leased for the player
+	lease: Lease,
+	target: SocketAddr,
+}
+
+/// A client talking to a target server through one relay socket
+#[derive(Debug)]
+pub struct Connection {
+	pub addr: SocketAddr,
+	/// Player ID sent in the initial connect message
+	pub user_id: u64,
+	/// Frontend socket the client connected to
+	/// Replies go out of it so they come from the address the client expects
+	pub ingress: TUdpSocket,
+	state: AtomicU8,
+	/// Taken when the connection closes so the lease is given back exactly once
+	route: Mutex<Option<Route>>,
+	/// Packets relayed before the connection was authenticated
+	/// Replayed to a new target when the player is migrated
+	handshake: Mutex<Vec<Vec<u8>>>,
+	opened: Instant,
+	/// Milliseconds after `opened` that the handshake completed
+	authenticated_at: AtomicU64,
+	/// Milliseconds after `opened` that the client last sent something
+	last_seen: AtomicU64,
+	/// Milliseconds after `opened` that the target server last sent something
+	backend_seen: AtomicU64,
+}
+
+impl Connection {
+	fn new(
+		addr: SocketAddr,
+		user_id: u64,
+		ingress: TUdpSocket,
+		lease: Lease,
+		target: SocketAddr,
+		first: Vec<u8>,
+	) -> Connection {
+		Connection {
+			addr,
+			user_id,
+			ingress,
+			state: AtomicU8::new(CONNECTING),
+			route: Mutex::new(Some(Route { lease, target })),
+			handshake: Mutex::new(vec![first]),
+			opened: Instant::now(),
+			authenticated_at: AtomicU64::new(0),
+			last_seen: AtomicU64::new(0),
+			backend_seen: AtomicU64::new(0),
+		}
+	}
+
+	fn millis(&self) -> u64 {
+		self.opened.elapsed().as_millis() as u64
+	}
+
+	fn transition(&self, from: u8, to: u8) -> bool {
+		self.state
+			.compare_exchange(from, to, Ordering::AcqRel, Ordering::Acquire)
+			.is_ok()
+	}
+
+	pub fn is_connecting(&self) -> bool {
+		self.state.load(Ordering::Acquire) == CONNECTING
+	}
+
+	pub fn is_authenticated(&self) -> bool {
+		self.state.load(Ordering::Acquire) == AUTHENTICATED
+	}
+
+	/// Complete the connect handshake
+	/// Only one packet can win when several arrive at once
+	pub fn authenticate(&self, payload: &[u8]) -> bool {
+		if !self.transition(CONNECTING, AUTHENTICATED) {
+			return false;
+		}
+		self.handshake.lock().unwrap().push(payload.to_vec());
+		let now = self.millis();
+		self.authenticated_at.store(now, Ordering::Release);
+		self.last_seen.store(now, Ordering::Release);
+		true
+	}
+
+	/// Update the message relay clock
+	pub fn touch(&self) {
+		self.last_seen.store(self.millis(), Ordering::Release);
+	}
+
+	/// Record traffic from the target server
+	pub fn touch_backend(&self) {
+		self.backend_seen.store(self.millis(), Ordering::Release);
+	}
+
+	/// Time since the client and the target server last sent something
+	/// Both are measured from the same instant so traffic in the same millisecond ties
+	pub fn idle(&self) -> (Duration, Duration) {
+		let now = self.opened.elapsed();
+		let since = |millis: &AtomicU64| {
+			now.saturating_sub(Duration::from_millis(millis.load(Ordering::Acquire)))
+		};
+		(since(&self.last_seen), since(&self.backend_seen))
+	}
+
+	pub fn last_seen(&self) -> Instant {
+		self.opened + Duration::from_millis(self.last_seen.load(Ordering::Acquire))
+	}
+
+	/// When the handshake completed
+	pub fn connected(&self) -> Option<Instant> {
+		self.is_authenticated().then(|| {
+			self.opened + Duration::from_millis(self.authenticated_at.load(Ordering::Acquire))
+		})
+	}
+
+	/// Relay socket and target server
+	/// Copied out so no lock is held while sending
+	pub fn relay(&self) -> Option<(TUdpSocket, SocketAddr)> {
+		self.route
+			.lock()
+			.unwrap()
+			.as_ref()
+			.map(|r| (r.lease.sock().clone(), r.target))
+	}
+
+	/// ID of the relay socket and the target server for events
+	pub fn relay_id(&self) -> Option<(usize, SocketAddr)> {
+		self.route
+			.lock()
+			.unwrap()
+			.as_ref()
+			.map(|r| (r.lease.sock().id(), r.target))
+	}
+
+	pub fn target(&self) -> Option<SocketAddr> {
+		self.route.lock().unwrap().as_ref().map(|r| r.target)
+	}
+
+	pub fn handshake(&self) -> Vec<Vec<u8>> {
+		self.handshake.lock().unwrap().clone()
+	}
+}
+
+/// What the master server told us about a player before it connects
+#[derive(Debug)]
+struct Registration {
+	username: String,
+	auth_token: String,
+	registered: Instant,
+}
+
+/// Everything known about a player the master server has sent us
+#[derive(Debug, Clone)]
+pub struct PlayerInfo {
+	pub user_id: u64,
+	pub username: String,
+	/// Token issued by the master server
+	/// Empty for players that connected without central auth
+	pub auth_token: String,
+	/// The following are set once the connect handshake succeeds
+	pub client: Option<SocketAddr>,
+	/// ID of the relay socket carrying the player's traffic
+	pub relay: Option<usize>,
+	pub target: Option<SocketAddr>,
+	pub connected: Option<Instant>,
+	pub last_seen: Instant,
+}
+
+/// Every connection indexed by client address, relay socket and user
+/// Connections are only ever added or removed from all indexes together
+#[derive(Debug, Default)]
+pub struct ConnTable {
+	by_addr: DashMap<SocketAddr, Arc<Connection>>,
+	/// Allows return relay threads to find the client
+	by_relay: DashMap<usize, Arc<Connection>>,
+	/// Latest authenticated connection for each user
+	by_user: DashMap<u64, Arc<Connection>>,
+	/// Players that have authenticated with the master server
+	registry: DashMap<u64, Registration>,
+}
+
+impl ConnTable {
+	pub fn get(&self, addr: &SocketAddr) -> Option<Arc<Connection>> {
+		self.by_addr.get(addr).map(|c| c.value().clone())
+	}
+
+	pub fn by_relay(&self, id: usize) -> Option<Arc<Connection>> {
+		self.by_relay.get(&id).map(|c| c.value().clone())
+	}
+
+	pub fn by_user(&self, user_id: u64) -> Option<Arc<Connection>> {
+		self.by_user.get(&user_id).map(|c| c.value().clone())
+	}
+
+	/// Snapshot of every connection
+	pub fn connections(&self) -> Vec<Arc<Connection>> {
+		self.by_addr.iter().map(|c| c.value().clone()).collect()
+	}
+
+	pub fn len(&self) -> usize {
+		self.by_addr.len()
+	}
+
+	/// Add a connection for an address
+	/// Hands the lease back when two connects from the same address race
+	pub fn open(
+		&self,
+		addr: SocketAddr,
+		user_id: u64,
+		ingress: TUdpSocket,
+		lease: Lease,
+		target: SocketAddr,
+		first: Vec<u8>,
+	) -> Result<Arc<Connection>, Lease> {
+		let conn = match self.by_addr.entry(addr) {
+			Entry::Occupied(_) => return Err(lease),
+			Entry::Vacant(v) => {
+				let relay = lease.sock().id();
+				let conn = Arc::new(Connection::new(
+					addr, user_id, ingress, lease, target, first,
+				));
+				self.by_relay.insert(relay, conn.clone());
+				v.insert(conn.clone());
+				conn
+			}
+		};
+		Ok(conn)
+	}
+
+	/// Complete a connection's handshake and make it the user's connection
+	/// Only authenticated connections are indexed by user
+	/// A connect claiming someone else's ID cannot displace them
+	pub fn authenticate(&self, conn: &Arc<Connection>, payload: &[u8]) -> bool {
+		if !conn.authenticate(payload) {
+			return false;
+		}
+		self.by_user.insert(conn.user_id, conn.clone());
+		// A close that ran before the insert could not remove it
+		if !conn.is_authenticated() {
+			self.by_user
+				.remove_if(&conn.user_id, |_, c| Arc::ptr_eq(c, conn));
+		}
+		true
+	}
+
+	/// Close a connection and remove it from every index
+	/// Returns the relay lease to whoever closed it
+	pub fn close(&self, conn: &Arc<Connection>) -> Option<Lease> {
+		if conn.state.swap(CLOSED, Ordering::AcqRel) == CLOSED {
+			return None;
+		}
+		self.unlink(conn)
+	}
+
+	/// Close a connection that has not finished its handshake
+	/// Does nothing if another packet authenticated it in the meantime
+	pub fn reject(&self, conn: &Arc<Connection>) -> Option<Lease> {
+		if !conn.transition(CONNECTING, CLOSED) {
+			return None;
+		}
+		self.unlink(conn)
+	}
+
+	fn unlink(&self, conn: &Arc<Connection>) -> Option<Lease> {
+		// Taken first so a migration in progress sees the connection is gone
+		let route = conn.route.lock().unwrap().take();
+		// Only this connection's entries are removed in case the address or user has moved on
+		self.by_addr
+			.remove_if(&conn.addr, |_, c| Arc::ptr_eq(c, conn));
+		self.by_user
+			.remove_if(&conn.user_id, |_, c| Arc::ptr_eq(c, conn));
+		let route = route?;
+		self.by_relay
+			.remove_if(&route.lease.sock().id(), |_, c| Arc::ptr_eq(c, conn));
+		Some(route.lease)
+	}
+
+	/// Send replies that reach a relay socket to a connection before it is routed there
+	/// Undone by `reroute` or `drop_replies`
+	pub fn route_replies(&self, conn: &Arc<Connection>, relay: usize) {
+		self.by_relay.insert(relay, conn.clone());
+	}
+
+	/// Stop sending replies from a relay socket the connection was never moved to
+	pub fn drop_replies(&self, conn: &Arc<Connection>, relay: usize) {
+		self.by_relay.remove_if(&relay, |_, c| Arc::ptr_eq(c, conn));
+	}
+
+	/// Move a connection to a new relay socket and target
+	/// Returns the old lease or the new one if the connection closed first
+	pub fn reroute(
+		&self,
+		conn: &Arc<Connection>,
+		lease: Lease,
+		target: SocketAddr,
+	) -> Result<(Lease, SocketAddr), Lease> {
+		// Route replies from the new relay before swapping so none are dropped
+		let relay = lease.sock().id();
+		self.route_replies(conn, relay);
+		let old = {
+			let mut route = conn.route.lock().unwrap();
+			match route.as_mut() {
+				Some(r) => Ok((
+					std::mem::replace(&mut r.lease, lease),
+					std::mem::replace(&mut r.target, target),
+				)),
+				None => Err(lease),
+			}
+		};
+		match old {
+			Ok((lease, target)) => {
+				self.by_relay
+					.remove_if(&lease.sock().id(), |_, c| Arc::ptr_eq(c, conn));
+				Ok((lease, target))
+			}
+			Err(lease) => {
+				self.drop_replies(conn, relay);
+				Err(lease)
+			}
+		}
+	}
+
+	/// Record or update a player sent by the master server
+	/// A reconnecting player keeps its connection until the new handshake replaces it
+	pub fn register(&self, user_id: u64, username: &str, auth_token: &str) {
+		let now = Instant::now();
+		self.registry
+			.entry(user_id)
+			.and_modify(|r| {
+				r.username = username.to_owned();
+				r.auth_token = auth_token.to_owned();
+				r.registered = now;
+			})
+			.or_insert_with(|| Registration {
+				username: username.to_owned(),
+				auth_token: auth_token.to_owned(),
+				registered: now,
+			});
+	}
+
+	/// Fill in the username of a player that connected without the master server
+	pub fn register_name(&self, user_id: u64, username: &str) {
+		let mut reg = self
+			.registry
+			.entry(user_id)
+			.or_insert_with(|| Registration {
+				username: String::new(),
+				auth_token: String::new(),
+				registered: Instant::now(),
+			});
+		if reg.username.is_empty() {
+			reg.username = username.to_owned();
+		}
+	}
+
+	/// Forget a player
+	/// Returns its username if it was registered
+	pub fn unregister(&self, user_id: u64) -> Option<String> {
+		self.registry.remove(&user_id).map(|(_, r)| r.username)
+	}
+
+	/// Forget players that are not connected unless `keep` holds for them
+	/// Returns the IDs that were removed
+	pub fn unregister_absent(&self, keep: impl Fn(u64) -> bool) -> Vec<u64> {
+		let mut gone = Vec::new();
+		self.registry.retain(|id, _| {
+			let stay = self.by_user.contains_key(id) || keep(*id);
+			if !stay {
+				gone.push(*id);
+			}
+			stay
+		});
+		gone
+	}
+
+	/// Name the master server gave for a player
+	/// Empty when the player is not registered
+	pub fn username(&self, user_id: u64) -> String {
+		self.registry
+			.get(&user_id)
+			.map(|r| r.username.clone())
+			.unwrap_or_default()
+	}
+
+	fn player_info(&self, user_id: u64, reg: &Registration) -> PlayerInfo {
+		let conn = self.by_user(user_id).filter(|c| c.is_authenticated());
+		let route = conn.as_ref().and_then(|c| c.relay_id());
+		PlayerInfo {
+			user_id,
+			username: reg.username.clone(),
+			auth_token: reg.auth_token.clone(),
+			client: conn.as_ref().map(|c| c.addr),
+			relay: route.map(|(id, _)| id),
+			target: route.map(|(_, target)| target),
+			connected: conn.as_ref().and_then(|c| c.connected()),
+			last_seen: conn
+				.as_ref()
+				.map_or(reg.registered, |c| c.last_seen().max(reg.registered)),
+		}
+	}
+
+	/// Registry entry for a player
+	pub fn player(&self, user_id: u64) -> Option<PlayerInfo> {
+		let reg = self.registry.get(&user_id)?;
+		Some(self.player_info(user_id, &reg))
+	}
+
+	/// Every registry entry including players that have not connected yet
+	pub fn players(&self) -> Vec<PlayerInfo> {
+		self.registry
+			.iter()
+			.map(|r| self.player_info(*r.key(), r.value()))
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use crate::pool::SocketPool;
+
+	fn addr(port: u16) -> SocketAddr {
+		SocketAddr::from(([127, 0, 0, 1], port))
+	}
+
+	#[test]
+	fn indexes_stay_in_step() {
+		let socks = TUdpSocket::bind_for_tests(3);
+		let pool = SocketPool::new(&socks[..2], 0);
+		let table = ConnTable::default();
+		let lease = pool.lease_player().unwrap();
+		let relay = lease.sock().id();
+		let conn = table
+			.open(addr(1), 7, socks[2].clone(), lease, addr(9), Vec::new())
+			.unwrap();
+		assert!(Arc::ptr_eq(&table.by_relay(relay).unwrap(), &conn));
+		// Only authenticated connections belong to the user
+		assert!(table.by_user(7).is_none());
+		assert!(table.authenticate(&conn, b"hello"));
+		assert!(Arc::ptr_eq(&table.by_user(7).unwrap(), &conn));
+		// A second connect from the same address gets its lease back
+		let lease = pool.lease_player().unwrap();
+		assert!(table
+			.open(addr(1), 7, socks[2].clone(), lease, addr(9), Vec::new())
+			.is_err());
+		assert!(table.close(&conn).is_some());
+		assert!(table.get(&addr(1)).is_none());
+		assert!(table.by_relay(relay).is_none());
+		assert!(table.by_user(7).is_none());
+		assert_eq!(table.len(), 0);
+	}
+
+	#[test]
+	fn leases_come_back_once() {
+		let socks = TUdpSocket::bind_for_tests(2);
+		let pool = SocketPool::new(&socks[..1], 0);
+		let table = ConnTable::default();
+		let lease = pool.lease_player().unwrap();
+		let conn = table
+			.open(addr(1), 7, socks[1].clone(), lease, addr(9), Vec::new())
+			.unwrap();
+		assert!(conn.authenticate(b"hello"));
+		// Authenticated connections are not rejected by a late bad packet
+		assert!(table.reject(&conn).is_none());
+		assert!(conn.is_authenticated());
+		assert!(table.close(&conn).is_some());
+		assert!(table.close(&conn).is_none());
+		assert!(!conn.authenticate(b"again"));
+	}
+
+	#[test]
+	fn reroute_moves_the_relay_index() {
+		let socks = TUdpSocket::bind_for_tests(3);
+		let pool = SocketPool::new(&socks[..2], 0);
+		let table = ConnTable::default();
+		let first = pool.lease_player().unwrap();
+		let second = pool.lease_player().unwrap();
+		let (old_id, new_id) = (first.sock().id(), second.sock().id());
+		let conn = table
+			.open(addr(1), 7, socks[2].clone(), first, addr(9), Vec::new())
+			.unwrap();
+		let (old, old_target) = table.reroute(&conn, second, addr(10)).unwrap();
+		assert_eq!(old.sock().id(), old_id);
+		assert_eq!(old_target, addr(9));
+		assert_eq!(conn.target(), Some(addr(10)));
+		assert!(table.by_relay(old_id).is_none());
+		assert!(table.by_relay(new_id).is_some());
+		// Rerouting a closed connection hands the new lease back
+		let lease = table.close(&conn).unwrap();
+		assert!(table.reroute(&conn, old, addr(9)).is_err());
+		assert!(table.by_relay(old_id).is_none());
+		pool.release(lease);
+	}
+}
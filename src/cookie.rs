@@ -0,0 +1,113 @@
+use crate::banlist::unix_now;
+
+use std::net::{IpAddr, SocketAddr};
+
+use {
+	hmac::{Hmac, Mac},
+	rand::{thread_rng, Rng},
+	sha2::Sha256,
+	subtle::ConstantTimeEq,
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Stateless connect challenges
+/// A client that echoes its cookie has proven it receives packets at its source address.
+/// Nothing is stored per client so spoofed floods cannot use up memory or relay sockets.
+#[derive(Debug)]
+pub struct CookieJar {
+	/// Regenerated on every start which invalidates any cookie in flight
+	secret: [u8; 32],
+}
+
+impl CookieJar {
+	pub fn new() -> CookieJar {
+		CookieJar {
+			secret: thread_rng().gen(),
+		}
+	}
+
+	fn mac(&self, addr: &SocketAddr, user_id: u64, epoch: u64) -> [u8; 8] {
+		let mut mac =
+			HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+		match addr.ip() {
+			IpAddr::V4(ip) => mac.update(&ip.octets()),
+			IpAddr::V6(ip) => mac.update(&ip.octets()),
+		}
+		mac.update(&addr.port().to_le_bytes());
+		mac.update(&user_id.to_le_bytes());
+		mac.update(&epoch.to_le_bytes());
+		let mut cookie = [0; 8];
+		cookie.copy_from_slice(&mac.finalize().into_bytes()[..8]);
+		cookie
+	}
+
+	/// Cookie for a client to echo back in its next connect
+	pub fn issue(&self, addr: &SocketAddr, user_id: u64, lifetime: u64) -> [u8; 8] {
+		self.mac(addr, user_id, unix_now() / lifetime)
+	}
+
+	pub fn verify(&self, addr: &SocketAddr, user_id: u64, cookie: &[u8], lifetime: u64) -> bool {
+		let epoch = unix_now() / lifetime;
+		// Cookies issued just before the epoch rolled over are still good
+		[epoch, epoch.saturating_sub(1)]
+			.iter()
+			.any(|e| self.mac(addr, user_id, *e).ct_eq(cookie).into())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const LIFETIME: u64 = 30;
+
+	fn addr(s: &str) -> SocketAddr {
+		s.parse().unwrap()
+	}
+
+	#[test]
+	fn issued_cookies_verify() {
+		let jar = CookieJar::new();
+		for client in ["10.0.0.1:37015", "[2001:db8::1]:37015"] {
+			let cookie = jar.issue(&addr(client), 7, LIFETIME);
+			assert!(jar.verify(&addr(client), 7, &cookie, LIFETIME));
+		}
+	}
+
+	#[test]
+	fn cookies_are_bound_to_the_client() {
+		let jar = CookieJar::new();
+		let client = addr("10.0.0.1:37015");
+		let cookie = jar.issue(&client, 7, LIFETIME);
+		assert!(!jar.verify(&addr("10.0.0.2:37015"), 7, &cookie, LIFETIME));
+		assert!(!jar.verify(&addr("10.0.0.1:37016"), 7, &cookie, LIFETIME));
+		assert!(!jar.verify(&client, 8, &cookie, LIFETIME));
+		// Another start of the proxy has a different secret
+		assert!(!CookieJar::new().verify(&client, 7, &cookie, LIFETIME));
+	}
+
+	#[test]
+	fn old_cookies_expire() {
+		let jar = CookieJar::new();
+		let client = addr("10.0.0.1:37015");
+		let epoch = unix_now() / LIFETIME;
+		// The previous epoch is still accepted
+		assert!(jar.verify(&client, 7, &jar.mac(&client, 7, epoch - 1), LIFETIME));
+		assert!(!jar.verify(&client, 7, &jar.mac(&client, 7, epoch - 2), LIFETIME));
+		assert!(!jar.verify(&client, 7, &jar.mac(&client, 7, epoch + 1), LIFETIME));
+	}
+
+	#[test]
+	fn tampered_cookies_fail() {
+		let jar = CookieJar::new();
+		let client = addr("10.0.0.1:37015");
+		let cookie = jar.issue(&client, 7, LIFETIME);
+		for i in 0..cookie.len() {
+			let mut tampered = cookie;
+			tampered[i] ^= 1;
+			assert!(!jar.verify(&client, 7, &tampered, LIFETIME), "{}", i);
+		}
+		assert!(!jar.verify(&client, 7, &cookie[..7], LIFETIME));
+	}
+}
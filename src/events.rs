@@ -0,0 +1,142 @@
+use crate::apperr::TitanfrontError;
+
+use std::{
+	fs::OpenOptions,
+	io::{self, LineWriter, Write},
+	net::SocketAddr,
+	sync::Mutex,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use {
+	anyhow::{Context, Result},
+	serde::Serialize,
+};
+
+/// Connection lifecycle events for moderation tooling
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+	/// Connect message from an unbound address
+	ConnectAttempt,
+	Authenticated,
+	/// Handshake used a token issued to another user
+	SpoofRejected,
+	/// Handshake used a token the master server never sent us
+	TokenMissing,
+	/// No relay socket was free for the player
+	SocketExhausted,
+	/// Bind was dropped for inactivity
+	Timeout,
+	/// Connect handshake was not completed in time
+	HandshakeTimeout,
+	Kicked,
+	Migrated,
+}
+
+/// A single line of the event log
+#[derive(Serialize, Debug)]
+pub struct Event {
+	/// Unix time in milliseconds
+	pub time: u64,
+	pub event: EventKind,
+	pub user_id: u64,
+	pub username: Option<String>,
+	pub client: Option<SocketAddr>,
+	/// ID of the relay socket carrying the player's traffic
+	pub relay: Option<usize>,
+	pub target: Option<SocketAddr>,
+	/// Free form context such as a kick reason
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub detail: Option<String>,
+}
+
+impl Event {
+	pub fn new(event: EventKind, user_id: u64, client: Option<SocketAddr>) -> Event {
+		Event {
+			time: SystemTime::now()
+				.duration_since(UNIX_EPOCH)
+				.map_or(0, |d| d.as_millis() as u64),
+			event,
+			user_id,
+			username: None,
+			client,
+			relay: None,
+			target: None,
+			detail: None,
+		}
+	}
+
+	pub fn username(mut self, username: &str) -> Event {
+		if !username.is_empty() {
+			self.username = Some(username.to_owned());
+		}
+		self
+	}
+
+	pub fn relay(mut self, relay: usize, target: SocketAddr) -> Event {
+		self.relay = Some(relay);
+		self.target = Some(target);
+		self
+	}
+
+	pub fn detail(mut self, detail: impl Into<String>) -> Event {
+		self.detail = Some(detail.into());
+		self
+	}
+}
+
+/// JSON lines sink for events
+#[derive(Default)]
+pub struct EventLog {
+	/// Events are dropped when unset
+	out: Option<Mutex<Box<dyn Write + Send>>>,
+}
+
+// Writers are not Debug
+impl std::fmt::Debug for EventLog {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		f.debug_struct("EventLog")
+			.field("enabled", &self.out.is_some())
+			.finish()
+	}
+}
+
+impl EventLog {
+	/// `-` writes to stdout and an empty path disables the log
+	pub fn open(path: &str) -> Result<EventLog> {
+		let out: Box<dyn Write + Send> = match path {
+			"" => return Ok(EventLog::default()),
+			"-" => Box::new(LineWriter::new(io::stdout())),
+			_ => Box::new(LineWriter::new(
+				OpenOptions::new()
+					.create(true)
+					.append(true)
+					.open(path)
+					.map_err(TitanfrontError::EventFile)
+					.with_context(|| format!("Error opening event log {}", path))?,
+			)),
+		};
+		log::info!("Writing connection events to {}", path);
+		Ok(EventLog {
+			out: Some(Mutex::new(out)),
+		})
+	}
+
+	pub fn emit(&self, event: Event) {
+		let out = match &self.out {
+			Some(o) => o,
+			None => return,
+		};
+		let line = match serde_json::to_string(&event) {
+			Ok(l) => l,
+			Err(e) => {
+				log::error!("Could not serialize event {:?}: {}", event, e);
+				return;
+			}
+		};
+		// A full disk should not take the proxy down with it
+		if let Err(e) = writeln!(out.lock().unwrap(), "{}", line) {
+			log::error!("Could not write event: {}", e);
+		}
+	}
+}
@@ -0,0 +1,221 @@
+use crate::{appconfig::AppConfig, router::Router};
+
+use std::{
+	fmt::Debug,
+	net::SocketAddr,
+	sync::atomic::{AtomicUsize, Ordering},
+	time::{Duration, Instant},
+};
+
+use {
+	dashmap::DashMap,
+	rand::{thread_rng, Rng},
+};
+
+/// Strategy names accepted by the `join_policy` config key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinPolicyKind {
+	/// Always use `join_target`
+	Fixed,
+	RoundRobin,
+	LeastConnected,
+	Weighted,
+	Sticky,
+}
+
+/// Picks the target server for a player that is not bound yet
+pub trait JoinPolicy: Debug + Send + Sync {
+	/// Index into `AppConfig::target_servers` for the new player
+	fn select(&self, user_id: u64, router: &Router, config: &AppConfig) -> usize;
+	/// Drop state the policy no longer needs
+	/// Called with the idle connection sweep
+	fn sweep(&self, _router: &Router, _config: &AppConfig) {}
+}
+
+pub fn from_config(config: &AppConfig) -> Box<dyn JoinPolicy> {
+	match config.join_policy {
+		JoinPolicyKind::Fixed => Box::new(Fixed {}),
+		JoinPolicyKind::RoundRobin => Box::new(RoundRobin {
+			next: AtomicUsize::new(0),
+		}),
+		JoinPolicyKind::LeastConnected => Box::new(LeastConnected {}),
+		JoinPolicyKind::Weighted => Box::new(Weighted {}),
+		JoinPolicyKind::Sticky => Box::new(Sticky {
+			assigned: DashMap::new(),
+		}),
+	}
+}
+
+fn least_loaded(router: &Router, config: &AppConfig) -> usize {
+	least_of(&router.target_loads(config))
+}
+
+/// Index of the smallest load
+/// Ties go to the earliest server in the list
+fn least_of(loads: &[usize]) -> usize {
+	loads
+		.iter()
+		.enumerate()
+		.min_by_key(|(_, l)| **l)
+		.map_or(0, |(i, _)| i)
+}
+
+#[derive(Debug)]
+struct Fixed {}
+
+impl JoinPolicy for Fixed {
+	fn select(&self, _user_id: u64, router: &Router, _config: &AppConfig) -> usize {
+		router.get_join_target()
+	}
+}
+
+#[derive(Debug)]
+struct RoundRobin {
+	next: AtomicUsize,
+}
+
+impl JoinPolicy for RoundRobin {
+	fn select(&self, _user_id: u64, _router: &Router, config: &AppConfig) -> usize {
+		self.next.fetch_add(1, Ordering::Relaxed) % config.target_servers.len()
+	}
+}
+
+/// Counts live binds on each target
+#[derive(Debug)]
+struct LeastConnected {}
+
+impl JoinPolicy for LeastConnected {
+	fn select(&self, _user_id: u64, router: &Router, config: &AppConfig) -> usize {
+		least_loaded(router, config)
+	}
+}
+
+/// Random choice weighted by `join_weights`
+#[derive(Debug)]
+struct Weighted {}
+
+impl JoinPolicy for Weighted {
+	fn select(&self, _user_id: u64, router: &Router, config: &AppConfig) -> usize {
+		// Servers without a weight get a weight of 1
+		let weights: Vec<u64> = (0..config.target_servers.len())
+			.map(|i| config.join_weights.get(i).copied().unwrap_or(1))
+			.collect();
+		let total: u64 = weights.iter().sum();
+		if total == 0 {
+			return router.get_join_target();
+		}
+		let mut pick = thread_rng().gen_range(0..total);
+		for (i, w) in weights.iter().enumerate() {
+			if pick < *w {
+				return i;
+			}
+			pick -= w;
+		}
+		unreachable!("Weighted pick exceeded total weight")
+	}
+}
+
+/// Sends returning players back to the server they were first assigned
+/// New players go to the least connected server
+/// Players are forgotten once they have been gone for `sticky_ttl`
+#[derive(Debug)]
+struct Sticky {
+	// Keyed on address rather than index so edits to the server list do not move players
+	assigned: DashMap<u64, (SocketAddr, Instant)>,
+}
+
+impl JoinPolicy for Sticky {
+	fn select(&self, user_id: u64, router: &Router, config: &AppConfig) -> usize {
+		if let Some(mut entry) = self.assigned.get_mut(&user_id) {
+			let (addr, seen) = entry.value_mut();
+			if let Some(i) = config.target_servers.iter().position(|t| t == addr) {
+				*seen = Instant::now();
+				return i;
+			}
+		}
+		let i = least_loaded(router, config);
+		self.assigned
+			.insert(user_id, (config.target_servers[i], Instant::now()));
+		i
+	}
+
+	fn sweep(&self, router: &Router, config: &AppConfig) {
+		let ttl = Duration::from_secs(config.sticky_ttl);
+		let now = Instant::now();
+		self.assigned.retain(|user_id, (_, seen)| {
+			// Time away only counts from when the player left
+			if router.is_connected(*user_id) {
+				*seen = now;
+			}
+			now.duration_since(*seen) < ttl
+		});
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const SERVERS: &str =
+		"target_servers = [\"127.0.0.1:37015\", \"127.0.0.1:37016\", \"127.0.0.1:37017\"]\n";
+
+	#[test]
+	fn round_robin_wraps_around() {
+		let config = AppConfig::for_tests(SERVERS);
+		let router = Router::for_tests(&[], &config);
+		let policy = RoundRobin {
+			next: AtomicUsize::new(0),
+		};
+		let picks: Vec<usize> = (0..7).map(|_| policy.select(1, &router, &config)).collect();
+		assert_eq!(picks, [0, 1, 2, 0, 1, 2, 0]);
+	}
+
+	#[test]
+	fn weighted_follows_weights() {
+		let config = AppConfig::for_tests(&format!("{}join_weights = [1, 0, 3]\n", SERVERS));
+		let router = Router::for_tests(&[], &config);
+		let policy = Weighted {};
+		let mut counts = [0; 3];
+		for _ in 0..8000 {
+			counts[policy.select(1, &router, &config)] += 1;
+		}
+		assert_eq!(counts[1], 0, "zero weight server was picked");
+		// Expect 2000 and 6000 with plenty of room for chance
+		assert!((1600..2400).contains(&counts[0]), "{:?}", counts);
+		assert!((5600..6400).contains(&counts[2]), "{:?}", counts);
+	}
+
+	#[test]
+	fn least_connected_ties_go_first() {
+		assert_eq!(least_of(&[2, 1, 1]), 1);
+		assert_eq!(least_of(&[0, 0, 0]), 0);
+		assert_eq!(least_of(&[3, 2, 0]), 2);
+		assert_eq!(least_of(&[]), 0);
+	}
+
+	#[test]
+	fn sticky_reuses_and_forgets() {
+		let config = AppConfig::for_tests(SERVERS);
+		let router = Router::for_tests(&[], &config);
+		let policy = Sticky {
+			assigned: DashMap::new(),
+		};
+		// Nobody is connected so new players go to the first server
+		assert_eq!(policy.select(1, &router, &config), 0);
+		let long_ago = Instant::now()
+			.checked_sub(Duration::from_secs(config.sticky_ttl + 1))
+			.unwrap();
+		policy
+			.assigned
+			.insert(2, (config.target_servers[2], Instant::now()));
+		policy
+			.assigned
+			.insert(3, (config.target_servers[1], long_ago));
+		assert_eq!(policy.select(2, &router, &config), 2);
+		policy.sweep(&router, &config);
+		assert!(policy.assigned.contains_key(&1));
+		assert!(policy.assigned.contains_key(&2));
+		assert!(!policy.assigned.contains_key(&3));
+		assert_eq!(policy.select(3, &router, &config), 0);
+	}
+}
@@ -1,29 +1,95 @@
 mod appconfig;
 mod apperr;
 mod authserver;
+mod banlist;
+mod cli;
+mod conntable;
+mod cookie;
+mod events;
+mod joinpolicy;
+mod metrics;
+mod packet;
+mod pool;
+mod ratelimit;
+mod reload;
 mod router;
 mod tsock;
 
 use crate::{
-	router::{external_handler, internal_handler, Router},
+	appconfig::{ConfigSource, SharedConfig},
+	cli::{Cli, Command},
+	reload::Reloader,
+	router::{external_handler, Router},
 	tsock::TUdpSocket,
 };
 
-use std::{panic, process, sync::Arc, time::Duration};
+use std::{panic, process, sync::Arc};
 
-use anyhow::Result;
-use tokio::time;
+use anyhow::{Context, Result};
+use arc_swap::ArcSwap;
+use clap::Parser;
+use tokio::signal;
+
+/// Resolves when the process is asked to stop
+async fn shutdown_signal() {
+	#[cfg(unix)]
+	{
+		let mut term = signal::unix::signal(signal::unix::SignalKind::terminate())
+			.expect("Failed to install SIGTERM handler");
+		tokio::select! {
+			_ = term.recv() => {}
+			_ = signal::ctrl_c() => {}
+		}
+	}
+	#[cfg(not(unix))]
+	{
+		signal::ctrl_c()
+			.await
+			.expect("Failed to install Ctrl-C handler");
+	}
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
 	env_logger::init();
 
+	let cli = Cli::parse();
+	if cli.check_config {
+		cli::check_config(&ConfigSource::default());
+	}
+	match cli.command {
+		None => run(ConfigSource::default()).await,
+		Some(Command::Run(args)) => run(args.source()).await,
+		Some(Command::CheckConfig(args)) => cli::check_config(&args.source()),
+		Some(Command::GenKey) => {
+			cli::gen_key();
+			Ok(())
+		}
+		Some(Command::Status(args)) => cli::status(&args).await,
+	}
+}
+
+async fn run(source: ConfigSource) -> Result<()> {
 	log::info!("Parsing config");
-	let conf = appconfig::AppConfig::new();
+	let conf = appconfig::AppConfig::load(&source)?;
 
 	log::info!("Create UDP sockets");
 	// Setup UDP relaying sockets
-	let proxy_sock = TUdpSocket::bind(conf.udp_address, usize::MAX).await?;
+	// Frontend IDs count down from the top so they never collide with relay sockets
+	let mut listeners: Vec<TUdpSocket> = Vec::with_capacity(conf.udp_addresses.len());
+	for (i, addr) in conf.udp_addresses.iter().enumerate() {
+		// Linux binds IPv6 sockets to both stacks so [::] and 0.0.0.0 on one port would clash
+		let v6_only = addr.is_ipv6()
+			&& conf
+				.udp_addresses
+				.iter()
+				.any(|a| a.is_ipv4() && a.port() == addr.port());
+		let listener = TUdpSocket::listen(*addr, usize::MAX - i, v6_only)
+			.with_context(|| format!("Could not listen on {}", addr))?;
+		// Shows the chosen port when bound to port 0
+		log::info!("Listening for players on {}", listener.local_addr()?);
+		listeners.push(listener);
+	}
 	let mut internal_sockets: Vec<TUdpSocket> = Vec::with_capacity(16);
 	log::info!("Binding UDP sockets");
 	for i in 0..conf.player_count + conf.admins.len() {
@@ -34,8 +100,14 @@ async fn main() -> Result<()> {
 		);
 	}
 
+	log::info!("Loading bans");
+	let bans = banlist::BanList::load(&conf.ban_file)?;
+
+	log::info!("Opening event log");
+	let events = events::EventLog::open(&conf.event_log)?;
+
 	log::info!("Create route tables");
-	let auth_tables = Arc::new(Router::new(&internal_sockets, conf.join_target));
+	let auth_tables = Arc::new(Router::new(&internal_sockets, &conf, bans, events));
 
 	let orig_hook = panic::take_hook();
 	panic::set_hook(Box::new(move |panic_info| {
@@ -44,40 +116,35 @@ async fn main() -> Result<()> {
 		process::exit(1);
 	}));
 
-	let conf_pointer = Arc::new(conf);
+	let conf_pointer: SharedConfig = Arc::new(ArcSwap::from_pointee(conf));
 
 	log::info!("Spawn server receive threads");
 	for s in internal_sockets {
+		auth_tables.spawn_relay(s, conf_pointer.clone());
+	}
+
+	log::info!("Spawn player receive threads");
+	for listener in listeners {
 		let cfg = conf_pointer.clone();
-		let prxy = proxy_sock.clone();
 		let tables = auth_tables.clone();
 		tokio::spawn(async move {
-			internal_handler(s, cfg, tables, prxy).await
-                // Thread errors cannot propagate back to the main thread
-                // If they are unhandled by now they are fatal errors
-                .unwrap();
+			external_handler(listener, cfg, tables).await
+				// Thread errors cannot propagate back to the main thread
+				// If they are unhandled by now they are fatal errors
+				.unwrap();
 		});
 	}
 
-	log::info!("Spawn player receive threads");
-	let cfg = conf_pointer.clone();
-	let prxy = proxy_sock.clone();
-	let tables = auth_tables.clone();
-	tokio::spawn(async move {
-		external_handler(prxy, cfg, tables).await
-			// Thread errors cannot propagate back to the main thread
-			// If they are unhandled by now they are fatal errors
-			.unwrap();
-	});
-
-	let cleaner_tables = auth_tables.clone();
-	tokio::spawn(async move {
-		let mut interval = time::interval(Duration::from_secs(5));
-		loop {
-			interval.tick().await;
-			cleaner_tables.cleanup_dead_connections().await;
-		}
-	});
+	auth_tables.spawn_cleanup(conf_pointer.clone());
+
+	let reloader = Arc::new(Reloader::new(
+		conf_pointer.clone(),
+		source,
+		auth_tables.clone(),
+	));
+	tokio::spawn(reload::watch(reloader.clone()));
 
-	authserver::build_and_run(auth_tables, conf_pointer.clone()).await
+	authserver::build_and_run(auth_tables, conf_pointer, reloader, shutdown_signal()).await?;
+	log::info!("Shutdown complete");
+	Ok(())
 }
@@ -0,0 +1,188 @@
+use std::{
+	fmt::Write,
+	net::SocketAddr,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Counters exported on /metrics
+// Relaxed ordering throughout. Scrapes only need eventually consistent values
+#[derive(Debug, Default)]
+pub struct Metrics {
+	/// Client to target server
+	pub packets_to_server: AtomicU64,
+	pub bytes_to_server: AtomicU64,
+	/// Target server to client
+	pub packets_to_client: AtomicU64,
+	pub bytes_to_client: AtomicU64,
+	pub connect_attempts: AtomicU64,
+	pub auth_failures: AtomicU64,
+	pub spoof_rejections: AtomicU64,
+	pub decrypt_failures: AtomicU64,
+	pub malformed_packets: AtomicU64,
+	pub tokens_expired: AtomicU64,
+	pub handshake_timeouts: AtomicU64,
+	pub rate_limited: AtomicU64,
+	pub cookies_issued: AtomicU64,
+	pub penalties: AtomicU64,
+	pub heartbeat_successes: AtomicU64,
+	pub heartbeat_failures: AtomicU64,
+}
+
+pub fn inc(counter: &AtomicU64) {
+	counter.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn add(counter: &AtomicU64, n: u64) {
+	counter.fetch_add(n, Ordering::Relaxed);
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+	// Writing to a String cannot fail
+	let _ = writeln!(out, "# HELP {} {}", name, help);
+	let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+impl Metrics {
+	/// Prometheus text exposition format
+	pub fn render(
+		&self,
+		free_sockets: usize,
+		penalized: usize,
+		loads: &[(SocketAddr, usize)],
+	) -> String {
+		let mut out = String::new();
+		let get = |c: &AtomicU64| c.load(Ordering::Relaxed);
+
+		header(
+			&mut out,
+			"titanfront_packets_relayed_total",
+			"counter",
+			"Packets relayed through the proxy",
+		);
+		let _ = writeln!(
+			out,
+			"titanfront_packets_relayed_total{{direction=\"to_server\"}} {}",
+			get(&self.packets_to_server)
+		);
+		let _ = writeln!(
+			out,
+			"titanfront_packets_relayed_total{{direction=\"to_client\"}} {}",
+			get(&self.packets_to_client)
+		);
+
+		header(
+			&mut out,
+			"titanfront_bytes_relayed_total",
+			"counter",
+			"Bytes relayed through the proxy",
+		);
+		let _ = writeln!(
+			out,
+			"titanfront_bytes_relayed_total{{direction=\"to_server\"}} {}",
+			get(&self.bytes_to_server)
+		);
+		let _ = writeln!(
+			out,
+			"titanfront_bytes_relayed_total{{direction=\"to_client\"}} {}",
+			get(&self.bytes_to_client)
+		);
+
+		let counters = [
+			(
+				"titanfront_connect_attempts_total",
+				"Connect messages from unbound addresses",
+				&self.connect_attempts,
+			),
+			(
+				"titanfront_auth_failures_total",
+				"Handshakes with unknown tokens and wrong passwords",
+				&self.auth_failures,
+			),
+			(
+				"titanfront_spoof_rejections_total",
+				"Handshakes using another user's token",
+				&self.spoof_rejections,
+			),
+			(
+				"titanfront_decrypt_failures_total",
+				"Packets that failed to decrypt",
+				&self.decrypt_failures,
+			),
+			(
+				"titanfront_malformed_packets_total",
+				"Packets dropped for being too short to parse",
+				&self.malformed_packets,
+			),
+			(
+				"titanfront_tokens_expired_total",
+				"Auth tokens that expired without being used",
+				&self.tokens_expired,
+			),
+			(
+				"titanfront_handshake_timeouts_total",
+				"Connects that never finished the handshake",
+				&self.handshake_timeouts,
+			),
+			(
+				"titanfront_rate_limited_total",
+				"Unauthenticated packets dropped by rate limits or the penalty box",
+				&self.rate_limited,
+			),
+			(
+				"titanfront_cookies_issued_total",
+				"Connect cookie challenges sent",
+				&self.cookies_issued,
+			),
+			(
+				"titanfront_penalties_total",
+				"Addresses put in the penalty box",
+				&self.penalties,
+			),
+			(
+				"titanfront_heartbeat_successes_total",
+				"Heartbeats accepted by the master server",
+				&self.heartbeat_successes,
+			),
+			(
+				"titanfront_heartbeat_failures_total",
+				"Heartbeats the master server did not accept",
+				&self.heartbeat_failures,
+			),
+		];
+		for (name, help, counter) in counters {
+			header(&mut out, name, "counter", help);
+			let _ = writeln!(out, "{} {}", name, get(counter));
+		}
+
+		header(
+			&mut out,
+			"titanfront_free_sockets",
+			"gauge",
+			"Relay sockets waiting for a player",
+		);
+		let _ = writeln!(out, "titanfront_free_sockets {}", free_sockets);
+
+		header(
+			&mut out,
+			"titanfront_penalized_addresses",
+			"gauge",
+			"Addresses ignored after repeated bad packets",
+		);
+		let _ = writeln!(out, "titanfront_penalized_addresses {}", penalized);
+
+		header(
+			&mut out,
+			"titanfront_active_binds",
+			"gauge",
+			"Live binds on each target server",
+		);
+		for (target, load) in loads {
+			let _ = writeln!(
+				out,
+				"titanfront_active_binds{{target=\"{}\"}} {}",
+				target, load
+			);
+		}
+		out
+	}
+}
@@ -0,0 +1,228 @@
+use crate::apperr::TitanfrontError;
+
+/// Leads every connect message a player sends
+pub const CONNECT_HEADER: [u8; 13] = [
+	0xFF, 0xFF, 0xFF, 0xFF, 0x48, 0x63, 0x6F, 0x6E, 0x6E, 0x65, 0x63, 0x74, 0x00,
+];
+const CHALLENGE_LEADER: [u8; 9] = [0xFF, 0xFF, 0xFF, 0xFF, 0x49, 0x54, 0x74, 0x46, 0x72];
+const CHALLENGE_TRAILER: [u8; 12] = [
+	0x63, 0x6F, 0x6E, 0x6E, 0x65, 0x63, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+/// Tokens are cut to this length by the game
+pub const TOKEN_LENGTH: usize = 31;
+
+fn read_u64(plain: &[u8], at: usize, what: &'static str) -> Result<u64, TitanfrontError> {
+	plain
+		.get(at..at + 8)
+		.map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+		.ok_or(TitanfrontError::Truncated(what))
+}
+
+fn read_challenge(plain: &[u8], at: usize, what: &'static str) -> Result<[u8; 8], TitanfrontError> {
+	plain
+		.get(at..at + 8)
+		.map(|b| b.try_into().unwrap())
+		.ok_or(TitanfrontError::Truncated(what))
+}
+
+pub fn is_connect(plain: &[u8]) -> bool {
+	plain.starts_with(&CONNECT_HEADER)
+}
+
+/// First connect from a player before it has a challenge
+/// The auth server sends the same message to check the server is reachable
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectRequest {
+	pub user_id: u64,
+}
+
+impl ConnectRequest {
+	pub fn decode(plain: &[u8]) -> Result<ConnectRequest, TitanfrontError> {
+		if !is_connect(plain) {
+			return Err(TitanfrontError::WrongMessage("connect request"));
+		}
+		Ok(ConnectRequest {
+			user_id: read_u64(plain, 13, "connect request")?,
+		})
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		[&CONNECT_HEADER[..], &self.user_id.to_le_bytes()].concat()
+	}
+}
+
+/// Challenge a player has to echo in its next connect
+/// Answers the auth server with its user ID as the challenge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+	pub challenge: [u8; 8],
+}
+
+impl Challenge {
+	pub fn decode(plain: &[u8]) -> Result<Challenge, TitanfrontError> {
+		if !plain.starts_with(&CHALLENGE_LEADER) {
+			return Err(TitanfrontError::WrongMessage("challenge"));
+		}
+		let challenge = read_challenge(plain, CHALLENGE_LEADER.len(), "challenge")?;
+		if !plain[CHALLENGE_LEADER.len() + 8..].starts_with(&CHALLENGE_TRAILER) {
+			return Err(TitanfrontError::Truncated("challenge"));
+		}
+		Ok(Challenge { challenge })
+	}
+
+	pub fn encode(&self) -> Vec<u8> {
+		[&CHALLENGE_LEADER[..], &self.challenge, &CHALLENGE_TRAILER].concat()
+	}
+}
+
+/// Connect that echoes a challenge and carries the player's identity
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengeResponse {
+	pub challenge: [u8; 8],
+	pub user_id: u64,
+	pub username: String,
+	pub token: String,
+}
+
+impl ChallengeResponse {
+	pub fn decode(plain: &[u8]) -> Result<ChallengeResponse, TitanfrontError> {
+		if !is_connect(plain) {
+			return Err(TitanfrontError::WrongMessage("connect response"));
+		}
+		let challenge = read_challenge(plain, 13, "connect response")?;
+		let user_id = read_u64(plain, 21, "connect response")?;
+		let rest = &plain[29..];
+		// The username is null terminated
+		let uname_end = rest
+			.iter()
+			.position(|b| *b == 0)
+			.ok_or(TitanfrontError::Truncated("username"))?;
+		// The token may be cut short by the end of the packet or another terminator
+		let token = &rest[uname_end + 1..];
+		let token = &token[..token.len().min(TOKEN_LENGTH)];
+		let token = token.split(|b| *b == 0).next().unwrap_or_default();
+		Ok(ChallengeResponse {
+			challenge,
+			user_id,
+			// Best effort. If someone knows the charset file a bug
+			username: String::from_utf8_lossy(&rest[..uname_end]).into_owned(),
+			// This is supposed to be hex. It had better work
+			token: String::from_utf8_lossy(token).into_owned(),
+		})
+	}
+
+	// Players are the only ones sending these
+	#[allow(dead_code)]
+	pub fn encode(&self) -> Vec<u8> {
+		[
+			&CONNECT_HEADER[..],
+			&self.challenge,
+			&self.user_id.to_le_bytes(),
+			self.username.as_bytes(),
+			&[0],
+			self.token.as_bytes(),
+		]
+		.concat()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn connect_request_round_trip() {
+		let req = ConnectRequest {
+			user_id: 1004329002390,
+		};
+		assert_eq!(ConnectRequest::decode(&req.encode()).unwrap(), req);
+	}
+
+	#[test]
+	fn connect_request_ignores_trailing_bytes() {
+		// Players pad the first connect with zeros
+		let mut plain = ConnectRequest { user_id: 7 }.encode();
+		plain.extend_from_slice(&[0; 8]);
+		assert_eq!(ConnectRequest::decode(&plain).unwrap().user_id, 7);
+	}
+
+	#[test]
+	fn challenge_round_trip() {
+		let challenge = Challenge {
+			challenge: [1, 2, 3, 4, 5, 6, 7, 8],
+		};
+		assert_eq!(Challenge::decode(&challenge.encode()).unwrap(), challenge);
+	}
+
+	#[test]
+	fn challenge_response_round_trip() {
+		let resp = ChallengeResponse {
+			challenge: [9; 8],
+			user_id: u64::MAX,
+			username: String::from("pilot"),
+			token: "a".repeat(TOKEN_LENGTH),
+		};
+		assert_eq!(ChallengeResponse::decode(&resp.encode()).unwrap(), resp);
+	}
+
+	#[test]
+	fn challenge_response_empty_fields() {
+		let resp = ChallengeResponse {
+			challenge: [0; 8],
+			user_id: 0,
+			username: String::new(),
+			token: String::new(),
+		};
+		assert_eq!(ChallengeResponse::decode(&resp.encode()).unwrap(), resp);
+	}
+
+	#[test]
+	fn challenge_response_cuts_long_tokens() {
+		let mut plain = ChallengeResponse {
+			challenge: [0; 8],
+			user_id: 1,
+			username: String::from("pilot"),
+			token: "b".repeat(TOKEN_LENGTH),
+		}
+		.encode();
+		plain.extend_from_slice(b"extra");
+		let resp = ChallengeResponse::decode(&plain).unwrap();
+		assert_eq!(resp.token, "b".repeat(TOKEN_LENGTH));
+	}
+
+	#[test]
+	fn short_packets_are_errors() {
+		let full = ChallengeResponse {
+			challenge: [3; 8],
+			user_id: 42,
+			username: String::from("pilot"),
+			token: String::from("token"),
+		}
+		.encode();
+		// Every cut before the username terminator leaves the message incomplete
+		for len in 0..CONNECT_HEADER.len() + 8 + 8 + 6 {
+			assert!(ChallengeResponse::decode(&full[..len]).is_err(), "{}", len);
+		}
+		for len in 0..CONNECT_HEADER.len() + 8 {
+			assert!(ConnectRequest::decode(&full[..len]).is_err(), "{}", len);
+		}
+		let challenge = Challenge { challenge: [3; 8] }.encode();
+		for len in 0..challenge.len() {
+			assert!(Challenge::decode(&challenge[..len]).is_err(), "{}", len);
+		}
+	}
+
+	#[test]
+	fn wrong_messages_are_errors() {
+		let challenge = Challenge { challenge: [0; 8] }.encode();
+		assert!(matches!(
+			ConnectRequest::decode(&challenge),
+			Err(TitanfrontError::WrongMessage(_))
+		));
+		let connect = ConnectRequest { user_id: 0 }.encode();
+		assert!(matches!(
+			Challenge::decode(&connect),
+			Err(TitanfrontError::WrongMessage(_))
+		));
+	}
+}
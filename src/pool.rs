@@ -0,0 +1,308 @@
+use crate::tsock::TUdpSocket;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crossbeam_queue::SegQueue;
+
+/// A relay socket handed out by the pool
+/// Leases cannot be cloned so a socket can only be given back once
+#[derive(Debug)]
+pub struct Lease {
+	sock: TUdpSocket,
+	/// Which queue the socket goes back to
+	admin: bool,
+}
+
+impl Lease {
+	pub fn sock(&self) -> &TUdpSocket {
+		&self.sock
+	}
+}
+
+/// Sockets of one kind
+#[derive(Debug, Default)]
+struct Slots {
+	free: SegQueue<TUdpSocket>,
+	/// Open sockets including leased ones
+	size: AtomicUsize,
+	/// Size to shrink to as leases come back
+	target: AtomicUsize,
+}
+
+impl Slots {
+	/// Take one socket off the size if the pool is above its target
+	fn shrink(&self) -> bool {
+		let target = self.target.load(Ordering::Acquire);
+		self.size
+			.fetch_update(Ordering::AcqRel, Ordering::Acquire, |size| {
+				(size > target).then(|| size - 1)
+			})
+			.is_ok()
+	}
+}
+
+/// Relay sockets waiting for a player
+/// Admin slots are kept in their own queue so players can never use them up
+#[derive(Debug, Default)]
+pub struct SocketPool {
+	players: Slots,
+	admins: Slots,
+}
+
+impl SocketPool {
+	/// The first `admin_slots` sockets are reserved for admins
+	pub fn new(sockets: &[TUdpSocket], admin_slots: usize) -> SocketPool {
+		let pool = SocketPool::default();
+		let admin_slots = admin_slots.min(sockets.len());
+		pool.set_target(true, admin_slots);
+		pool.set_target(false, sockets.len() - admin_slots);
+		for (i, sock) in sockets.iter().enumerate() {
+			pool.add(i < admin_slots, sock.clone());
+		}
+		pool
+	}
+
+	fn slots(&self, admin: bool) -> &Slots {
+		if admin {
+			&self.admins
+		} else {
+			&self.players
+		}
+	}
+
+	pub fn lease_player(&self) -> Option<Lease> {
+		self.players
+			.free
+			.pop()
+			.map(|sock| Lease { sock, admin: false })
+	}
+
+	/// Admins may also take player slots once their own run out
+	pub fn lease_admin(&self) -> Option<Lease> {
+		match self.admins.free.pop() {
+			Some(sock) => Some(Lease { sock, admin: true }),
+			None => self.lease_player(),
+		}
+	}
+
+	/// Give a lease back
+	/// Returns the socket when the pool is above its target and it should be closed
+	pub fn release(&self, lease: Lease) -> Option<TUdpSocket> {
+		let slots = self.slots(lease.admin);
+		if slots.shrink() {
+			Some(lease.sock)
+		} else {
+			slots.free.push(lease.sock);
+			None
+		}
+	}
+
+	/// Take a leased socket out of the pool for good
+	/// The pool is left short so a replacement can be bound
+	pub fn discard(&self, lease: Lease) -> TUdpSocket {
+		self.slots(lease.admin).size.fetch_sub(1, Ordering::AcqRel);
+		lease.sock
+	}
+
+	/// Add a newly bound socket
+	pub fn add(&self, admin: bool, sock: TUdpSocket) {
+		let slots = self.slots(admin);
+		slots.size.fetch_add(1, Ordering::AcqRel);
+		slots.free.push(sock);
+	}
+
+	/// Change how many sockets of a kind should be open
+	/// Free sockets above the target are returned to be closed.
+	/// Leased ones are closed as they come back.
+	pub fn set_target(&self, admin: bool, target: usize) -> Vec<TUdpSocket> {
+		let slots = self.slots(admin);
+		slots.target.store(target, Ordering::Release);
+		let mut retired = Vec::new();
+		while let Some(sock) = slots.free.pop() {
+			if slots.shrink() {
+				retired.push(sock);
+			} else {
+				slots.free.push(sock);
+				break;
+			}
+		}
+		retired
+	}
+
+	/// Sockets that have to be bound to reach the target
+	pub fn shortfall(&self, admin: bool) -> usize {
+		let slots = self.slots(admin);
+		slots
+			.target
+			.load(Ordering::Acquire)
+			.saturating_sub(slots.size.load(Ordering::Acquire))
+	}
+
+	/// Open sockets including leased ones
+	pub fn size(&self) -> usize {
+		self.players.size.load(Ordering::Acquire) + self.admins.size.load(Ordering::Acquire)
+	}
+
+	pub fn free(&self) -> usize {
+		self.players.free.len() + self.admins.free.len()
+	}
+
+	pub fn free_players(&self) -> usize {
+		self.players.free.len()
+	}
+}
+
+/// Packet buffers reused between tasks so the receive path does not allocate
+#[derive(Debug)]
+pub struct BufferPool {
+	free: SegQueue<Vec<u8>>,
+	buf_size: usize,
+	/// Buffers kept once they come back
+	/// More are allocated under load and dropped afterwards
+	max: usize,
+}
+
+impl BufferPool {
+	pub fn new(buf_size: usize, max: usize) -> BufferPool {
+		BufferPool {
+			free: SegQueue::new(),
+			buf_size,
+			max,
+		}
+	}
+
+	/// An empty buffer with room for one datagram
+	pub fn take(&self) -> Vec<u8> {
+		self.free
+			.pop()
+			.unwrap_or_else(|| Vec::with_capacity(self.buf_size))
+	}
+
+	pub fn give(&self, mut buf: Vec<u8>) {
+		if self.free.len() < self.max {
+			buf.clear();
+			self.free.push(buf);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::{
+		sync::{atomic::AtomicBool, Mutex},
+		thread,
+	};
+
+	#[test]
+	fn admin_slots_are_reserved() {
+		let pool = SocketPool::new(&TUdpSocket::bind_for_tests(4), 1);
+		let leases: Vec<Lease> = std::iter::from_fn(|| pool.lease_player()).collect();
+		assert_eq!(leases.len(), 3);
+		let admin = pool.lease_admin().unwrap();
+		assert!(pool.lease_admin().is_none());
+		pool.release(admin);
+		assert!(pool.lease_player().is_none());
+		for lease in leases {
+			assert!(pool.release(lease).is_none());
+		}
+		assert_eq!(pool.free(), 4);
+		assert_eq!(pool.free_players(), 3);
+	}
+
+	#[test]
+	fn admins_fall_back_to_player_slots() {
+		let pool = SocketPool::new(&TUdpSocket::bind_for_tests(2), 1);
+		let first = pool.lease_admin().unwrap();
+		let second = pool.lease_admin().unwrap();
+		assert!(pool.lease_admin().is_none());
+		pool.release(first);
+		pool.release(second);
+		// Each socket went back to the queue it came from
+		assert_eq!(pool.free_players(), 1);
+		assert_eq!(pool.free(), 2);
+	}
+
+	#[test]
+	fn shrinking_closes_returned_leases() {
+		let pool = SocketPool::new(&TUdpSocket::bind_for_tests(4), 0);
+		let first = pool.lease_player().unwrap();
+		let second = pool.lease_player().unwrap();
+		// Only the free sockets can be closed straight away
+		assert_eq!(pool.set_target(false, 1).len(), 2);
+		assert_eq!(pool.size(), 2);
+		assert!(pool.release(first).is_some());
+		assert!(pool.release(second).is_none());
+		assert_eq!(pool.size(), 1);
+		assert_eq!(pool.free(), 1);
+		assert_eq!(pool.set_target(false, 3).len(), 0);
+		assert_eq!(pool.shortfall(false), 2);
+	}
+
+	/// Hammer the pool from many threads and check no socket is ever leased twice
+	#[test]
+	fn no_socket_is_leased_twice() {
+		const THREADS: usize = 8;
+		const ROUNDS: usize = 20_000;
+		let socks = TUdpSocket::bind_for_tests(16);
+		let pool = SocketPool::new(&socks, 4);
+		let in_use: Vec<AtomicBool> = socks.iter().map(|_| AtomicBool::new(false)).collect();
+		let retired = Mutex::new(Vec::new());
+		thread::scope(|s| {
+			for t in 0..THREADS {
+				let (pool, in_use, retired) = (&pool, &in_use, &retired);
+				s.spawn(move || {
+					let mut held = Vec::new();
+					for i in 0..ROUNDS {
+						let lease = if (t + i) % 3 == 0 {
+							pool.lease_admin()
+						} else {
+							pool.lease_player()
+						};
+						if let Some(lease) = lease {
+							let was = in_use[lease.sock().id()].swap(true, Ordering::AcqRel);
+							assert!(!was, "socket {} leased twice", lease.sock().id());
+							held.push(lease);
+						}
+						// Hold up to a few leases so the queues run dry now and then
+						if held.len() > t % 3 {
+							let lease = held.remove(0);
+							in_use[lease.sock().id()].store(false, Ordering::Release);
+							if let Some(sock) = pool.release(lease) {
+								retired.lock().unwrap().push(sock.id());
+							}
+						}
+						// Shrink and grow the pool while it is in use
+						if t == 0 && i % 1000 == 0 {
+							let target = if i % 2000 == 0 { 6 } else { 12 };
+							for sock in pool.set_target(false, target) {
+								retired.lock().unwrap().push(sock.id());
+							}
+						}
+					}
+					for lease in held {
+						in_use[lease.sock().id()].store(false, Ordering::Release);
+						if let Some(sock) = pool.release(lease) {
+							retired.lock().unwrap().push(sock.id());
+						}
+					}
+				});
+			}
+		});
+		// Every socket is either free or closed and never both
+		let retired = retired.into_inner().unwrap();
+		assert_eq!(pool.size() + retired.len(), socks.len());
+		let mut seen = retired;
+		while let Some(lease) = pool.lease_admin() {
+			seen.push(lease.sock().id());
+		}
+		seen.sort_unstable();
+		seen.dedup();
+		assert_eq!(
+			seen.len(),
+			socks.len(),
+			"a socket was lost or handed out twice"
+		);
+	}
+}
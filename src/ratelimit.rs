@@ -0,0 +1,274 @@
+use crate::appconfig::AppConfig;
+
+use std::{
+	net::IpAddr,
+	sync::Mutex,
+	time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+
+/// Per IP buckets idle this long are full again and can be dropped
+const IDLE_BUCKET: Duration = Duration::from_secs(60);
+/// Most addresses given their own bucket
+/// Spoofed floods would otherwise add one for every forged address
+const MAX_BUCKETS: usize = 65536;
+
+#[derive(Debug)]
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+impl Bucket {
+	fn full(burst: u64) -> Bucket {
+		Bucket {
+			tokens: burst as f64,
+			last: Instant::now(),
+		}
+	}
+
+	/// Refill for the time since the last packet then take a token if there is one
+	fn take(&mut self, rate: u64, burst: u64) -> bool {
+		let now = Instant::now();
+		let refill = now.duration_since(self.last).as_secs_f64() * rate as f64;
+		self.tokens = (self.tokens + refill).min(burst as f64);
+		self.last = now;
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// Token bucket limits for packets from players that have not authenticated
+/// Addresses that keep sending bad connects are ignored for a while
+#[derive(Debug)]
+pub struct RateLimiter {
+	per_ip: DashMap<IpAddr, Bucket>,
+	global: Mutex<Bucket>,
+	/// Bad packet count and time of the last one
+	failures: DashMap<IpAddr, (u32, Instant)>,
+	/// Addresses ignored until the given time
+	penalty_box: DashMap<IpAddr, Instant>,
+}
+
+impl RateLimiter {
+	pub fn new(config: &AppConfig) -> RateLimiter {
+		RateLimiter {
+			per_ip: DashMap::new(),
+			global: Mutex::new(Bucket::full(config.rate_limit_global_burst)),
+			failures: DashMap::new(),
+			penalty_box: DashMap::new(),
+		}
+	}
+
+	/// Whether a packet from an unauthenticated address should be processed
+	pub fn allow(&self, ip: IpAddr, config: &AppConfig) -> bool {
+		if let Some(until) = self.penalty_box.get(&ip) {
+			if *until > Instant::now() {
+				return false;
+			}
+		}
+		let (rate, burst) = (config.rate_limit_per_ip, config.rate_limit_per_ip_burst);
+		// A rate of 0 turns the limit off
+		let tracked = match self.per_ip.get_mut(&ip) {
+			Some(mut bucket) if rate > 0 => {
+				if !bucket.take(rate, burst) {
+					return false;
+				}
+				true
+			}
+			_ => false,
+		};
+		if config.rate_limit_global > 0
+			&& !self
+				.global
+				.lock()
+				.unwrap()
+				.take(config.rate_limit_global, config.rate_limit_global_burst)
+		{
+			return false;
+		}
+		// New addresses only get a bucket once the global limit lets them through
+		if rate > 0 && !tracked && self.per_ip.len() < MAX_BUCKETS {
+			self.per_ip
+				.entry(ip)
+				.or_insert_with(|| Bucket::full(burst))
+				.take(rate, burst);
+		}
+		true
+	}
+
+	/// Count a bad connect from an address
+	/// Returns true when this puts the address in the penalty box
+	pub fn record_failure(&self, ip: IpAddr, config: &AppConfig) -> bool {
+		if config.penalty_threshold == 0 {
+			return false;
+		}
+		let duration = Duration::from_secs(config.penalty_duration);
+		let now = Instant::now();
+		// Packets already being processed when the address was boxed
+		if self.penalty_box.get(&ip).is_some_and(|until| *until > now) {
+			return false;
+		}
+		let mut entry = self.failures.entry(ip).or_insert((0, now));
+		// Failures spread out over longer than the penalty are forgiven
+		if entry.1.elapsed() > duration {
+			entry.0 = 0;
+		}
+		entry.0 += 1;
+		entry.1 = now;
+		if entry.0 < config.penalty_threshold {
+			return false;
+		}
+		entry.0 = 0;
+		drop(entry);
+		self.penalty_box.insert(ip, now + duration);
+		log::warn!(
+			"Ignoring {} for {}s after repeated bad packets",
+			ip,
+			config.penalty_duration
+		);
+		true
+	}
+
+	/// Forget state that no longer affects any decision
+	pub fn sweep(&self, config: &AppConfig) {
+		let now = Instant::now();
+		let duration = Duration::from_secs(config.penalty_duration);
+		self.per_ip.retain(|_, b| b.last.elapsed() < IDLE_BUCKET);
+		self.failures
+			.retain(|_, (_, last)| last.elapsed() < duration);
+		self.penalty_box.retain(|_, until| *until > now);
+	}
+
+	/// Addresses currently being ignored
+	pub fn penalized(&self) -> usize {
+		let now = Instant::now();
+		self.penalty_box
+			.iter()
+			.filter(|kv| *kv.value() > now)
+			.count()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ip(last: u8) -> IpAddr {
+		IpAddr::from([10, 0, 0, last])
+	}
+
+	#[test]
+	fn buckets_refill() {
+		let mut bucket = Bucket::full(2);
+		assert!(bucket.take(10, 2));
+		assert!(bucket.take(10, 2));
+		assert!(!bucket.take(10, 2));
+		// A tenth of a second at 10 per second is one packet
+		bucket.last -= Duration::from_millis(100);
+		assert!(bucket.take(10, 2));
+		assert!(!bucket.take(10, 2));
+		// Never more than the burst however long it was idle
+		bucket.last -= Duration::from_secs(60);
+		assert!(bucket.take(10, 2));
+		assert!(bucket.take(10, 2));
+		assert!(!bucket.take(10, 2));
+	}
+
+	#[test]
+	fn addresses_have_their_own_limit() {
+		let config = AppConfig::for_tests(
+			"rate_limit_per_ip = 1\n\
+			rate_limit_per_ip_burst = 2\n",
+		);
+		let limiter = RateLimiter::new(&config);
+		assert!(limiter.allow(ip(1), &config));
+		assert!(limiter.allow(ip(1), &config));
+		assert!(!limiter.allow(ip(1), &config));
+		assert!(limiter.allow(ip(2), &config));
+	}
+
+	#[test]
+	fn floods_past_the_global_limit_add_no_buckets() {
+		let config = AppConfig::for_tests(
+			"rate_limit_per_ip = 1\n\
+			rate_limit_per_ip_burst = 1\n\
+			rate_limit_global = 1\n\
+			rate_limit_global_burst = 3\n",
+		);
+		let limiter = RateLimiter::new(&config);
+		let allowed = (0..100).filter(|i| limiter.allow(ip(*i), &config)).count();
+		assert_eq!(allowed, 3);
+		assert_eq!(limiter.per_ip.len(), 3);
+		// Addresses over their own limit do not use up the global one
+		limiter.global.lock().unwrap().tokens = 1.0;
+		assert!(!limiter.allow(ip(0), &config));
+		assert!(limiter.allow(ip(200), &config));
+	}
+
+	#[test]
+	fn repeated_failures_are_penalized() {
+		let config = AppConfig::for_tests(
+			"penalty_threshold = 3\n\
+			penalty_duration = 30\n",
+		);
+		let limiter = RateLimiter::new(&config);
+		assert!(!limiter.record_failure(ip(1), &config));
+		assert!(!limiter.record_failure(ip(1), &config));
+		assert!(limiter.allow(ip(1), &config));
+		assert!(limiter.record_failure(ip(1), &config));
+		assert!(!limiter.allow(ip(1), &config));
+		assert!(limiter.allow(ip(2), &config));
+		assert_eq!(limiter.penalized(), 1);
+		// Packets already in flight do not box it again
+		assert!(!limiter.record_failure(ip(1), &config));
+		// The penalty lapses
+		*limiter.penalty_box.get_mut(&ip(1)).unwrap() = Instant::now();
+		assert!(limiter.allow(ip(1), &config));
+		assert_eq!(limiter.penalized(), 0);
+	}
+
+	#[test]
+	fn spread_out_failures_are_forgiven() {
+		let config = AppConfig::for_tests(
+			"penalty_threshold = 2\n\
+			penalty_duration = 30\n",
+		);
+		let limiter = RateLimiter::new(&config);
+		assert!(!limiter.record_failure(ip(1), &config));
+		limiter.failures.get_mut(&ip(1)).unwrap().1 -= Duration::from_secs(31);
+		assert!(!limiter.record_failure(ip(1), &config));
+		assert!(limiter.record_failure(ip(1), &config));
+	}
+
+	#[test]
+	fn sweeps_drop_stale_state() {
+		let config = AppConfig::for_tests(
+			"rate_limit_per_ip = 1\n\
+			penalty_threshold = 5\n\
+			penalty_duration = 30\n",
+		);
+		let limiter = RateLimiter::new(&config);
+		for i in 1..=2 {
+			limiter.allow(ip(i), &config);
+			limiter.record_failure(ip(i), &config);
+			limiter
+				.penalty_box
+				.insert(ip(i), Instant::now() + Duration::from_secs(30));
+		}
+		limiter.per_ip.get_mut(&ip(1)).unwrap().last -= IDLE_BUCKET;
+		limiter.failures.get_mut(&ip(1)).unwrap().1 -= Duration::from_secs(30);
+		*limiter.penalty_box.get_mut(&ip(1)).unwrap() = Instant::now();
+		limiter.sweep(&config);
+		for (addr, kept) in [(ip(1), false), (ip(2), true)] {
+			assert_eq!(limiter.per_ip.contains_key(&addr), kept);
+			assert_eq!(limiter.failures.contains_key(&addr), kept);
+			assert_eq!(limiter.penalty_box.contains_key(&addr), kept);
+		}
+	}
+}
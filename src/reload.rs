@@ -0,0 +1,188 @@
+use crate::{
+	appconfig::{AppConfig, ConfigSource, SharedConfig},
+	router::Router,
+};
+
+use std::{
+	fs,
+	sync::{
+		atomic::{AtomicU64, Ordering},
+		Arc,
+	},
+	time::{Duration, SystemTime},
+};
+
+use {
+	anyhow::{Context, Result},
+	tokio::{select, sync::Mutex, time},
+};
+
+/// Applies config file edits to a running server
+#[derive(Debug)]
+pub struct Reloader {
+	config: SharedConfig,
+	source: ConfigSource,
+	router: Arc<Router>,
+	/// Bumped on every successful reload so the heartbeat can push the changes
+	generation: AtomicU64,
+	/// Modification time of the config files when they were last read
+	/// Also stops two reloads from running at once
+	mtime: Mutex<Option<SystemTime>>,
+}
+
+/// Modification time of the config file
+/// Without an explicit path this is the newest `Titanfront.*` file in the working directory
+fn config_mtime(source: &ConfigSource) -> Option<SystemTime> {
+	if let Some(path) = &source.path {
+		return fs::metadata(path).ok()?.modified().ok();
+	}
+	fs::read_dir(".")
+		.ok()?
+		.filter_map(|e| e.ok())
+		.filter(|e| e.file_name().to_string_lossy().starts_with("Titanfront."))
+		.filter_map(|e| e.metadata().ok()?.modified().ok())
+		.max()
+}
+
+/// Undo edits to settings that are only read at startup
+fn keep<T: PartialEq + Clone>(name: &str, old: &T, new: &mut T) {
+	if old != new {
+		log::warn!(
+			"{} cannot change without a restart. Keeping the old value",
+			name
+		);
+		new.clone_from(old);
+	}
+}
+
+impl Reloader {
+	pub fn new(config: SharedConfig, source: ConfigSource, router: Arc<Router>) -> Reloader {
+		Reloader {
+			mtime: Mutex::new(config_mtime(&source)),
+			config,
+			source,
+			router,
+			generation: AtomicU64::new(0),
+		}
+	}
+
+	pub fn generation(&self) -> u64 {
+		self.generation.load(Ordering::Acquire)
+	}
+
+	/// Read the config again and swap it in
+	/// The running config is left alone if the new one is bad
+	pub async fn reload(&self) -> Result<()> {
+		let mut mtime = self.mtime.lock().await;
+		// Record the attempt even if it fails so a broken file is not retried every poll
+		*mtime = config_mtime(&self.source);
+		let old = self.config.load_full();
+		let mut new = AppConfig::load(&self.source).context("Error reloading config")?;
+		keep("key", &old.key, &mut new.key);
+		keep("udp_addresses", &old.udp_addresses, &mut new.udp_addresses);
+		keep("auth_address", &old.auth_address, &mut new.auth_address);
+		keep("relay_address", &old.relay_address, &mut new.relay_address);
+		keep(
+			"receive_buf_size",
+			&old.receive_buf_size,
+			&mut new.receive_buf_size,
+		);
+		keep(
+			"udp_batch_size",
+			&old.udp_batch_size,
+			&mut new.udp_batch_size,
+		);
+		keep("auth_enabled", &old.auth_enabled, &mut new.auth_enabled);
+		keep("auth_server", &old.auth_server, &mut new.auth_server);
+		keep("ban_file", &old.ban_file, &mut new.ban_file);
+		keep("event_log", &old.event_log, &mut new.event_log);
+		keep("join_policy", &old.join_policy, &mut new.join_policy);
+		keep("version", &old.version, &mut new.version);
+		// A target set through the admin API stays unless the file changes it
+		// Checked against the new list before it goes live so nothing indexes past its end
+		if new.join_target != old.join_target
+			|| self.router.get_join_target() >= new.target_servers.len()
+		{
+			self.router.set_join_target(new.join_target, &new)?;
+		}
+		let new = Arc::new(new);
+		self.config.store(new.clone());
+		self.router
+			.resize_pool(new.player_count, new.admins.len(), &self.config)
+			.await?;
+		self.generation.fetch_add(1, Ordering::Release);
+		log::info!("Config reloaded");
+		Ok(())
+	}
+
+	/// Reload if a config file was touched since it was last read
+	pub async fn reload_if_changed(&self) -> Result<()> {
+		if *self.mtime.lock().await == config_mtime(&self.source) {
+			return Ok(());
+		}
+		log::info!("Config file changed");
+		self.reload().await
+	}
+}
+
+/// Reload on SIGHUP and whenever the config file changes
+pub async fn watch(reloader: Arc<Reloader>) {
+	#[cfg(unix)]
+	let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+		.expect("Failed to install SIGHUP handler");
+	let mut poll = time::interval(Duration::from_secs(2));
+	loop {
+		#[cfg(unix)]
+		let hup = hangup.recv();
+		#[cfg(not(unix))]
+		let hup = std::future::pending::<Option<()>>();
+		let watch_file = reloader.config.load().watch_config;
+		let res = select! {
+			_ = hup => {
+				log::info!("SIGHUP received");
+				reloader.reload().await
+			}
+			_ = poll.tick(), if watch_file => reloader.reload_if_changed().await,
+		};
+		if let Err(e) = res {
+			log::error!("{:#}", e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use arc_swap::ArcSwap;
+
+	const THREE: &str =
+		"target_servers = [\"127.0.0.1:37016\", \"127.0.0.1:37017\", \"127.0.0.1:37018\"]\n";
+	const TWO: &str = "target_servers = [\"127.0.0.1:37016\", \"127.0.0.1:37017\"]\n";
+
+	#[tokio::test]
+	async fn reloads_keep_the_admin_join_target() {
+		let source = ConfigSource::for_tests();
+		source.write_for_tests(&format!("{}player_count = 1\n", THREE));
+		let conf = AppConfig::load(&source).unwrap();
+		let router = Arc::new(Router::for_tests(&[], &conf));
+		let config: SharedConfig = Arc::new(ArcSwap::from_pointee(conf));
+		let reloader = Reloader::new(config.clone(), source.clone(), router.clone());
+		let set = |target| router.set_join_target(target, &config.load()).unwrap();
+
+		set(2);
+		source.write_for_tests(&format!("{}player_count = 1\nname = \"renamed\"\n", THREE));
+		reloader.reload().await.unwrap();
+		assert_eq!(router.get_join_target(), 2);
+		// Changing the file's join target still applies it
+		source.write_for_tests(&format!("{}player_count = 1\njoin_target = 1\n", THREE));
+		reloader.reload().await.unwrap();
+		assert_eq!(router.get_join_target(), 1);
+		// As does a list too short for the current one
+		set(2);
+		source.write_for_tests(&format!("{}player_count = 1\njoin_target = 1\n", TWO));
+		reloader.reload().await.unwrap();
+		assert_eq!(router.get_join_target(), 1);
+		source.remove_for_tests();
+	}
+}
@@ -1,13 +1,30 @@
-use crate::{appconfig::AppConfig, apperr::TitanfrontError, tsock::TUdpSocket, Err};
+use crate::{
+	appconfig::{AppConfig, SharedConfig},
+	apperr::TitanfrontError,
+	banlist::{Ban, BanList},
+	conntable::{ConnTable, Connection, PlayerInfo},
+	cookie::CookieJar,
+	events::{Event, EventKind, EventLog},
+	joinpolicy::{self, JoinPolicy},
+	metrics::{self, Metrics},
+	packet::{self, Challenge, ChallengeResponse, ConnectRequest, CONNECT_HEADER},
+	pool::{BufferPool, Lease, SocketPool},
+	ratelimit::RateLimiter,
+	tsock::TUdpSocket,
+	Err,
+};
+
+#[cfg(target_os = "linux")]
+use crate::tsock::RecvBatch;
 
 use std::{
 	collections::HashSet,
 	net::{IpAddr, SocketAddr, ToSocketAddrs},
 	sync::{
-		atomic::{AtomicUsize, Ordering},
+		atomic::{AtomicBool, AtomicUsize, Ordering},
 		Arc,
 	},
-	time::Instant,
+	time::{Duration, Instant},
 };
 
 use {
@@ -15,72 +32,116 @@ use {
 	anyhow::{Context, Result},
 	dashmap::DashMap,
 	rand::{thread_rng, Rng},
-	tokio::sync::RwLock,
+	tokio::{sync::mpsc, task::JoinHandle},
 };
 
-const PLAYER_CONNECT_MESSAGE: [u8; 13] = [
-	0xFF, 0xFF, 0xFF, 0xFF, 0x48, 0x63, 0x6F, 0x6E, 0x6E, 0x65, 0x63, 0x74, 0x00,
-];
-const CHALLENGE_AUTH_SERVER_MESSAGE_LEADER: [u8; 9] =
-	[0xFF, 0xFF, 0xFF, 0xFF, 0x49, 0x54, 0x74, 0x46, 0x72];
-const CHALLENGE_AUTH_SERVER_MESSAGE_TRAILER: [u8; 12] = [
-	0x63, 0x6F, 0x6E, 0x6E, 0x65, 0x63, 0x74, 0x00, 0x00, 0x00, 0x00, 0x00,
-];
 const AAD: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+/// Nonce and tag in front of every encrypted packet
+const ENCRYPTION_OVERHEAD: usize = 28;
+/// Handshake packet copies kept for reuse
+const SPARE_BUFFERS: usize = 256;
 
-#[derive(PartialEq, Debug)]
-enum ConnStat {
-	Connecting,
-	Authenticated,
-	Blocked,
-}
-
+/// Auth token sent by the master server that has not been used yet
 #[derive(Debug)]
-struct Bind {
-	status: ConnStat,
-	sock: TUdpSocket,
-	target: SocketAddr,
+struct IssuedToken {
+	user_id: u64,
+	issued: Instant,
 }
 
-#[derive(Debug)]
-struct PlayerInfo {}
+impl IssuedToken {
+	fn is_live(&self, ttl: Duration) -> bool {
+		self.issued.elapsed() < ttl
+	}
+}
 
 #[derive(Debug)]
 pub struct Router {
 	/// Map auth tokens to user IDs to prevent spoofing
-	tokens: DashMap<String, u64>,
-	/// Map of client addresses to relay sockets
-	ips: DashMap<SocketAddr, Bind>,
-	/// Allows return relay threads to find client
-	sockets: DashMap<TUdpSocket, SocketAddr>,
-	// Dashmap is not really benchmarked for an update workload
-	// Others are fine but this may be slow
-	/// Socket use timestamps
-	/// Used to disconnect dead sessions
-	counters: DashMap<SocketAddr, Instant>,
-	// TODO: consider making non blocking
-	/// Available relay sockets
-	available: RwLock<Vec<TUdpSocket>>,
-	players: DashMap<u64, PlayerInfo>,
+	tokens: DashMap<String, IssuedToken>,
+	/// Connections and the players the master server has sent us
+	conns: ConnTable,
+	/// Relay sockets waiting for a player
+	pool: SocketPool,
+	/// Copies of packets handed to handshake tasks
+	buffers: BufferPool,
+	/// ID given to the next relay socket bound
+	next_socket_id: AtomicUsize,
+	/// Receive task of each relay socket so retired sockets can be closed
+	relay_tasks: DashMap<usize, JoinHandle<()>>,
+	/// Relay sockets waiting for a target's challenge while a player migrates
+	migrations: DashMap<usize, mpsc::Sender<Vec<u8>>>,
 	join_target: AtomicUsize,
+	/// Chooses a target server for new players
+	policy: Box<dyn JoinPolicy>,
+	bans: BanList,
+	metrics: Metrics,
+	events: EventLog,
+	/// Limits packets from players that have not authenticated
+	limiter: RateLimiter,
+	/// Issues and checks connect cookies when `connect_cookies` is on
+	cookies: CookieJar,
+	/// Set on shutdown to stop new players from connecting
+	draining: AtomicBool,
 }
 
-fn decrypt(ctext: &[u8], config: &AppConfig) -> Vec<u8> {
+fn decrypt(ctext: &[u8], config: &AppConfig) -> Result<Vec<u8>, TitanfrontError> {
+	if ctext.len() < ENCRYPTION_OVERHEAD {
+		return Err(TitanfrontError::Truncated("encrypted packet"));
+	}
 	let key = generic_array::GenericArray::clone_from_slice(&config.key);
 	let tag = generic_array::GenericArray::clone_from_slice(&ctext[12..28]);
 	let mut ptext = Vec::new();
 	ptext.extend_from_slice(&ctext[28..]);
 	let cipher = Aes128Gcm::new(&key);
 	let nonce = Nonce::from_slice(&ctext[0..12]);
-	match cipher.decrypt_in_place_detached(nonce, &AAD, &mut ptext, &tag) {
-		Ok(_) => {}
-		Err(e) => {
-			// TODO: Break out some error types and stop dropping this
-			log::error!("bad decrypt: {:?}", e);
+	cipher
+		.decrypt_in_place_detached(nonce, &AAD, &mut ptext, &tag)
+		.map_err(|_| TitanfrontError::Decrypt())?;
+	Ok(ptext)
+}
+
+/// How long connections may stay quiet before they are closed
+#[derive(Debug, Clone, Copy)]
+struct Timeouts {
+	handshake: Duration,
+	idle: Duration,
+	/// Extra time when the target server went quiet first
+	backend_grace: Duration,
+}
+
+impl Timeouts {
+	fn new(config: &AppConfig) -> Timeouts {
+		Timeouts {
+			handshake: Duration::from_secs(config.handshake_timeout),
+			idle: Duration::from_secs(config.idle_timeout),
+			backend_grace: Duration::from_secs(config.backend_grace),
 		}
 	}
 
-	ptext.to_vec()
+	/// Why a connection should be closed, if it should
+	/// Connecting clients get the handshake timeout counted from their first connect.
+	/// Once authenticated traffic either way keeps a connection alive.
+	/// Servers stop sending during map changes so they get longer when they went quiet first.
+	fn expired(&self, connecting: bool, client: Duration, backend: Duration) -> Option<EventKind> {
+		if connecting {
+			return (client > self.handshake).then_some(EventKind::HandshakeTimeout);
+		}
+		let limit = if backend > client {
+			self.idle + self.backend_grace
+		} else {
+			self.idle
+		};
+		(client.min(backend) > limit).then_some(EventKind::Timeout)
+	}
+}
+
+/// Event about a connection tagged with its relay socket and target
+fn relay_event(kind: EventKind, user_id: u64, conn: &Connection) -> Event {
+	let event = Event::new(kind, user_id, Some(conn.addr));
+	match conn.relay_id() {
+		Some((relay, target)) => event.relay(relay, target),
+		None => event,
+	}
 }
 
 fn encrypt(ptext: &[u8], config: &AppConfig) -> Vec<u8> {
@@ -98,214 +159,875 @@ fn encrypt(ptext: &[u8], config: &AppConfig) -> Vec<u8> {
 
 impl Router {
 	// There isn't any reason to convert to a
-	pub fn new(internal_sockets: &[TUdpSocket], join_target: usize) -> Router {
+	pub fn new(
+		internal_sockets: &[TUdpSocket],
+		config: &AppConfig,
+		bans: BanList,
+		events: EventLog,
+	) -> Router {
 		Router {
 			tokens: DashMap::new(),
-			ips: DashMap::new(),
-			sockets: DashMap::new(),
-			counters: DashMap::new(),
-			available: RwLock::new(internal_sockets.to_owned()),
-			players: DashMap::new(),
-			join_target: join_target.into(),
-		}
-	}
-	pub async fn add_token(&self, token: String, id: u64, conf: &AppConfig) -> Result<(), ()> {
-		let avail = self.available.read().await;
-		if avail.len() - conf.admins.len() > 0 {
-			self.tokens.insert(token, id);
+			conns: ConnTable::default(),
+			pool: SocketPool::new(internal_sockets, config.admins.len()),
+			buffers: BufferPool::new(config.receive_buf_size, SPARE_BUFFERS),
+			next_socket_id: internal_sockets.len().into(),
+			relay_tasks: DashMap::new(),
+			migrations: DashMap::new(),
+			join_target: config.join_target.into(),
+			policy: joinpolicy::from_config(config),
+			bans,
+			metrics: Metrics::default(),
+			events,
+			limiter: RateLimiter::new(config),
+			cookies: CookieJar::new(),
+			draining: AtomicBool::new(false),
+		}
+	}
+	pub fn metrics(&self) -> &Metrics {
+		&self.metrics
+	}
+	pub fn bans(&self) -> &BanList {
+		&self.bans
+	}
+	pub async fn add_token(
+		&self,
+		token: String,
+		id: u64,
+		username: String,
+		conf: &AppConfig,
+	) -> Result<(), ()> {
+		// Admins can also use their reserved slots
+		let free = if conf.admins.contains(&id) {
+			self.pool.free()
+		} else {
+			self.pool.free_players()
+		};
+		if free > 0 {
+			// Drop the oldest tokens so a user can never hold more than the cap
+			let mut outstanding: Vec<(String, Instant)> = self
+				.tokens
+				.iter()
+				.filter(|kv| kv.value().user_id == id)
+				.map(|kv| (kv.key().clone(), kv.value().issued))
+				.collect();
+			if outstanding.len() >= conf.max_tokens_per_user {
+				outstanding.sort_by_key(|(_, issued)| *issued);
+				let excess = outstanding.len() + 1 - conf.max_tokens_per_user;
+				for (old, _) in outstanding.iter().take(excess) {
+					self.tokens.remove(old);
+				}
+				log::info!("Revoked {} unused tokens for {}", excess, id);
+			}
+			self.tokens.insert(
+				token.clone(),
+				IssuedToken {
+					user_id: id,
+					issued: Instant::now(),
+				},
+			);
+			self.conns.register(id, &username, &token);
 			Ok(())
 		} else {
 			Err(())
 		}
 	}
-	async fn relay_external(&self, payload: &Vec<u8>, addr: &SocketAddr, config: &AppConfig) {
-		match self.ips.get_mut(addr) {
-			Some(mut pair) => {
-				match pair.value().status {
-					ConnStat::Authenticated => {
-						pair.value()
-							.sock
-							.send_to(payload, pair.value().target)
-							.await;
-						// Update the message relay clock
-						// Used to identify which players can be dropped for inactivity
-						self.counters.insert(*addr, Instant::now());
-						return;
-					}
-					ConnStat::Connecting => {
-						let plain = decrypt(payload, config);
-						let user_id = u64::from_le_bytes(plain[21..29].try_into().unwrap());
-						let mut uname_end: usize = 29;
-						// Iterate the username until we find a null terminator
-						// Clippy is wrong here the alternative is even longer
-						#[allow(clippy::needless_range_loop)]
-						for i in 29..payload.len() {
-							if payload[i] == 0 {
-								uname_end = i;
-								break;
-							}
-						}
-						// Best effort. If someone knows the charset file a bug
-						let user_name = String::from_utf8_lossy(&payload[29..uname_end]);
-
-						if !config.auth_enabled {
-							log::info!("Unauthenticated connection from {}:{}", user_id, user_name);
-							pair.value_mut().status = ConnStat::Authenticated;
-							pair.value()
-								.sock
-								.send_to(payload, pair.value().target)
-								.await;
+	/// Whether the master server sent a user a token they have not used yet
+	fn holds_token(&self, user_id: u64, config: &AppConfig) -> bool {
+		let ttl = Duration::from_secs(config.token_ttl);
+		self.tokens
+			.iter()
+			.any(|t| t.user_id == user_id && t.is_live(ttl))
+	}
+
+	/// Forward a packet from an authenticated player
+	/// Returns false when the address has no authenticated connection
+	async fn relay_established(&self, payload: &[u8], addr: &SocketAddr) -> bool {
+		let conn = match self.conns.get(addr) {
+			Some(c) if c.is_authenticated() => c,
+			_ => return false,
+		};
+		// Copied out so no lock is held across the send
+		let (sock, target) = match conn.relay() {
+			Some(r) => r,
+			None => return false,
+		};
+		if let Err(e) = sock.send_to(payload, target).await {
+			log::warn!("Could not relay packet to {}: {}", target, e);
+		}
+		metrics::inc(&self.metrics.packets_to_server);
+		metrics::add(&self.metrics.bytes_to_server, payload.len() as u64);
+		// Used to identify which players can be dropped for inactivity
+		conn.touch();
+		true
+	}
+
+	async fn relay_external(
+		&self,
+		payload: &[u8],
+		addr: &SocketAddr,
+		ingress: &TUdpSocket,
+		config: &AppConfig,
+	) {
+		match self.conns.get(addr) {
+			Some(conn) => {
+				// Another packet may have completed the handshake first
+				if !conn.is_connecting() {
+					self.relay_established(payload, addr).await;
+					return;
+				}
+				let identity =
+					match decrypt(payload, config).and_then(|p| ChallengeResponse::decode(&p)) {
+						Ok(i) => i,
+						Err(e) => {
+							self.drop_packet(addr, e, config);
 							return;
 						}
+					};
+				let user_id = identity.user_id;
+				let user_name = identity.username;
 
-						// This is supposed to be hex. It had better work
-						let token = String::from_utf8_lossy(&payload[uname_end..uname_end + 31]);
-						// The Cow has to be dereferenced
-						match self.tokens.get(&*token) {
-							Some(kv) => {
-								if kv.value() == &user_id {
-									log::info!(
-										"Connection with token from {}:{}",
-										user_id,
-										user_name
-									);
-									pair.value_mut().status = ConnStat::Authenticated;
-									pair.value()
-										.sock
-										.send_to(payload, pair.value().target)
-										.await;
-									return;
-								} else {
-									log::info!(
-										"Connection denied due to user {} spoofing {}:{}",
-										kv.value(),
-										user_id,
-										user_name
-									);
-									return;
-								}
-							}
-							None => {
-								// Mark the IP blocked until we can delete it without deadlocking
-								log::warn!(
-									"Failed auth from {}:{} with token {}",
-									user_id,
-									user_name,
-									token
-								);
-								pair.value_mut().status = ConnStat::Blocked;
-								self.sockets.remove(&pair.value().sock);
-								self.available.write().await.push(pair.value().sock.clone());
-							}
-						}
-					}
-					// Midway through cleanup on another thread.
-					// Do nothing
-					ConnStat::Blocked => {
-						log::warn!("Connection on blocked socket");
+				if user_id != conn.user_id {
+					// The slot, allow list and ban checks were made for the first connect's ID
+					log::warn!(
+						"Connection denied. {}:{} connected as {}",
+						user_id,
+						user_name,
+						conn.user_id
+					);
+					metrics::inc(&self.metrics.spoof_rejections);
+					self.events.emit(
+						relay_event(EventKind::SpoofRejected, user_id, &conn)
+							.username(&user_name)
+							.detail(format!("First connect was from {}", conn.user_id)),
+					);
+				} else if let Some(ban) = self.bans.check(Some(user_id), Some(addr.ip())) {
+					// Checked again here because the username is only sent in this message
+					log::warn!(
+						"Connection denied. {}:{} is banned: {}",
+						user_id,
+						user_name,
+						ban.reason
+					);
+				} else if !config.auth_enabled {
+					log::info!("Unauthenticated connection from {}:{}", user_id, user_name);
+					self.complete_handshake(&conn, payload, &user_name).await;
+					return;
+				} else {
+					let token = identity.token;
+					let ttl = Duration::from_secs(config.token_ttl);
+					// Tokens are single use so a leaked token cannot be replayed
+					let consumed = self
+						.tokens
+						.remove_if(&token, |_, t| t.user_id == user_id && t.is_live(ttl))
+						.is_some();
+					// Live tokens that were issued to someone else
+					let owner = self
+						.tokens
+						.get(&token)
+						.filter(|t| t.is_live(ttl))
+						.map(|t| t.user_id);
+					if consumed {
+						log::info!("Connection with token from {}:{}", user_id, user_name);
+						self.complete_handshake(&conn, payload, &user_name).await;
+						return;
+					} else if let Some(owner) = owner {
+						log::info!(
+							"Connection denied due to user {} spoofing {}:{}",
+							owner,
+							user_id,
+							user_name
+						);
+						metrics::inc(&self.metrics.spoof_rejections);
+						self.events.emit(
+							relay_event(EventKind::SpoofRejected, user_id, &conn)
+								.username(&user_name)
+								.detail(format!("Token belongs to {}", owner)),
+						);
 						return;
+					} else {
+						// Expired and already used tokens end up here too
+						log::warn!(
+							"Failed auth from {}:{} with token {}",
+							user_id,
+							user_name,
+							token
+						);
+						metrics::inc(&self.metrics.auth_failures);
+						self.events.emit(
+							relay_event(EventKind::TokenMissing, user_id, &conn)
+								.username(&user_name),
+						);
 					}
 				}
+				log::info!("Cleaning up closed socket");
+				if let Some(lease) = self.conns.reject(&conn) {
+					self.release_socket(lease);
+				}
 			}
 			None => {
-				{
-					// Explicit lifetime of read
-					// We use unwrap because it only errors on panic
-					if (self.available.read().await).len() == 0 {
+				if self.pool.free() == 0 {
+					return;
+				}
+				let plain = match decrypt(payload, config) {
+					Ok(p) => p,
+					Err(e) => {
+						self.drop_packet(addr, e, config);
 						return;
 					}
+				};
+				if !packet::is_connect(&plain) {
+					log::warn!("Connection blocked. Bad packet");
+					if self.limiter.record_failure(addr.ip(), config) {
+						metrics::inc(&self.metrics.penalties);
+					}
+					return;
 				}
-				let plain = decrypt(payload, config);
-				if plain.as_slice()[..13] == PLAYER_CONNECT_MESSAGE {
-					let mut available = self.available.write().await;
-					let user_id = u64::from_le_bytes(plain[13..21].try_into().unwrap());
-					if (available.len() > config.admins.len()
-						&& self.players.contains_key(&user_id))
-						|| (available.len() > 0 && config.admins.contains(&user_id))
-					{
-						// We can safely unwrap here because `available.len()` must be greater than 0
-						let sock = available.pop().unwrap();
-						// Ideally writes should always beat reads but we can't really guarantee correctness here
-						// Connecting will take a long time for users so having a write beat reads is ideal
-						// Relaxed reads with Acquire for writes is as close as we can get
-						// ALSO ATOMICS BEHAVE DIFFERENTLY ON INTELx86-AMD64 AND ARM
-						// THIS CODE IS NOT GUARANTEED TO BE CONSISTENT ACROSS PLATFORMS
-						// SEE: https://doc.rust-lang.org/nomicon/atomics.html#hardware-reordering
-						let target =
-							config.target_servers[self.join_target.load(Ordering::Relaxed)];
-						sock.send_to(payload, target).await;
-						self.ips.insert(
-							*addr,
-							Bind {
-								status: ConnStat::Connecting,
-								sock: sock.clone(),
-								target,
-							},
-						);
-						self.sockets.insert(sock, *addr);
+				metrics::inc(&self.metrics.connect_attempts);
+				if self.is_draining() {
+					log::info!("Connection blocked. Shutting down");
+					return;
+				}
+				let first_id = match ConnectRequest::decode(&plain) {
+					Ok(req) => req.user_id,
+					Err(e) => {
+						self.drop_packet(addr, e, config);
 						return;
-					} else {
-						log::warn!("Connection blocked. Not enough sockets");
+					}
+				};
+				let (user_id, first) = if config.connect_cookies {
+					// An echo carries the cookie where the game puts its challenge
+					let echo = ChallengeResponse::decode(&plain).ok();
+					let echoed = echo.as_ref().is_some_and(|resp| {
+						self.cookies.verify(
+							addr,
+							resp.user_id,
+							&resp.challenge,
+							config.cookie_lifetime,
+						)
+					});
+					// A first connect's ID would be the echoed cookie here
+					let uid = echo.map_or(first_id, |resp| resp.user_id);
+					if !echoed {
+						self.send_cookie(uid, addr, ingress, config).await;
 						return;
 					}
+					// The target never saw the original connect so it is rebuilt for the handshake
+					(
+						uid,
+						encrypt(&ConnectRequest { user_id: uid }.encode(), config),
+					)
 				} else {
-					log::warn!("Connection blocked. Bad packet");
+					(first_id, payload.to_vec())
+				};
+				// This message carries no username so fall back to the registry
+				let user_name = self.conns.username(user_id);
+				self.events.emit(
+					Event::new(EventKind::ConnectAttempt, user_id, Some(*addr))
+						.username(&user_name),
+				);
+				if let Some(ban) = self.bans.check(Some(user_id), Some(addr.ip())) {
+					log::warn!(
+						"Connection blocked. {}:{} is banned: {}",
+						user_id,
+						user_name,
+						ban.reason
+					);
+					return;
+				}
+				if !config.is_allowed(user_id) {
+					log::warn!("Connection blocked. {} is not on the allow list", user_id);
+					return;
+				}
+				// Without central auth nobody is sent ahead of time
+				if config.auth_enabled && !self.holds_token(user_id, config) {
+					log::warn!("Connection blocked. {} has no live token", user_id);
 					return;
 				}
+				let lease = if config.admins.contains(&user_id) {
+					self.pool.lease_admin()
+				} else {
+					self.pool.lease_player()
+				};
+				let lease = match lease {
+					Some(l) => l,
+					None => {
+						log::warn!("Connection blocked. Not enough sockets");
+						self.events.emit(
+							Event::new(EventKind::SocketExhausted, user_id, Some(*addr))
+								.username(&user_name),
+						);
+						return;
+					}
+				};
+				let index = self.policy.select(user_id, self, config);
+				// A reload can swap in a shorter list while the join target still points past it
+				let target = match config
+					.target_servers
+					.get(index)
+					.or(config.target_servers.first())
+				{
+					Some(t) => *t,
+					None => {
+						self.release_socket(lease);
+						return;
+					}
+				};
+				let sock = lease.sock().clone();
+				// Two connects from the same address can race here
+				match self.conns.open(
+					*addr,
+					user_id,
+					ingress.clone(),
+					lease,
+					target,
+					first.clone(),
+				) {
+					Ok(_) => {
+						if let Err(e) = sock.send_to(&first, target).await {
+							log::warn!("Could not relay packet to {}: {}", target, e);
+						}
+					}
+					Err(lease) => self.release_socket(lease),
+				}
 			}
 		}
-		log::info!("Cleaning up closed socket");
-		// TODO: clean up this removal
-		// It has to go outside the scope of the switch's borrow or it might race
-		// Alternately use a struct that does not race so much
-		let kv = self.ips.remove(addr);
-		// Check to make sure we are not deleting in use IPs
-		assert!(kv.unwrap().1.status == ConnStat::Blocked);
 	}
 
-	async fn relay_internal(&self, payload: &[u8], sender: &TUdpSocket, proxy: &TUdpSocket) {
-		if let Some(pair) = self.sockets.get(sender) {
-			proxy.send_to(payload, pair.value()).await;
+	/// Authenticate a connection and pass the handshake on to the target
+	async fn complete_handshake(&self, conn: &Arc<Connection>, payload: &[u8], user_name: &str) {
+		if !self.conns.authenticate(conn, payload) {
+			return;
 		}
+		if let Some((sock, target)) = conn.relay() {
+			if let Err(e) = sock.send_to(payload, target).await {
+				log::warn!("Could not relay packet to {}: {}", target, e);
+			}
+		}
+		self.conns.register_name(conn.user_id, user_name);
+		self.events
+			.emit(relay_event(EventKind::Authenticated, conn.user_id, conn).username(user_name));
 	}
 
-	pub fn get_player_count(&self) -> u64 {
-		self.ips.len() as u64
+	/// Count a packet that could not be parsed and drop it
+	/// Senders of bad packets are counted towards the penalty box
+	fn drop_packet(&self, addr: &SocketAddr, err: TitanfrontError, config: &AppConfig) {
+		log::warn!("Dropped packet from {}: {}", addr, err);
+		match err {
+			TitanfrontError::Decrypt() => metrics::inc(&self.metrics.decrypt_failures),
+			_ => metrics::inc(&self.metrics.malformed_packets),
+		}
+		if self.limiter.record_failure(addr.ip(), config) {
+			metrics::inc(&self.metrics.penalties);
+		}
 	}
 
-	pub async fn cleanup_dead_connections(&self) {
-		let mut deletes: Vec<SocketAddr> = Vec::new();
-		for refm in self.counters.iter() {
-			let (sock, instant) = refm.pair();
-			if instant.elapsed().as_secs() > 5 {
-				let mut bind = match self.ips.get_mut(&sock) {
-					Some(b) => b,
-					None => {
-						panic!("Failed cleanup! No bind for {:?}", sock);
+	/// Answer a connect with a cookie challenge instead of allocating a relay
+	/// Uses the same challenge message the proxy sends the auth server
+	async fn send_cookie(
+		&self,
+		user_id: u64,
+		addr: &SocketAddr,
+		ingress: &TUdpSocket,
+		config: &AppConfig,
+	) {
+		let challenge = Challenge {
+			challenge: self.cookies.issue(addr, user_id, config.cookie_lifetime),
+		}
+		.encode();
+		match ingress.send_to(&encrypt(&challenge, config), *addr).await {
+			Ok(_) => metrics::inc(&self.metrics.cookies_issued),
+			Err(e) => log::warn!("Could not send connect cookie to {}: {}", addr, e),
+		}
+	}
+
+	/// Whether a packet should be processed
+	/// Only addresses without an authenticated bind are rate limited
+	fn admit(&self, addr: &SocketAddr, config: &AppConfig) -> bool {
+		if self.conns.get(addr).is_some_and(|c| c.is_authenticated()) {
+			return true;
+		}
+		if self.limiter.allow(addr.ip(), config) {
+			return true;
+		}
+		metrics::inc(&self.metrics.rate_limited);
+		false
+	}
+
+	/// Addresses ignored after repeated bad packets
+	pub fn penalized(&self) -> usize {
+		self.limiter.penalized()
+	}
+
+	/// Registry entry for a player
+	pub fn get_player(&self, user_id: u64) -> Option<PlayerInfo> {
+		self.conns.player(user_id)
+	}
+
+	/// Every registry entry including players that have not connected yet
+	pub fn list_players(&self) -> Vec<PlayerInfo> {
+		self.conns.players()
+	}
+
+	/// Ban a user or network and disconnect anyone it covers
+	pub async fn add_ban(&self, ban: Ban) -> Result<()> {
+		let matched: Vec<u64> = self
+			.conns
+			.connections()
+			.iter()
+			.filter(|c| ban.matches(Some(c.user_id), Some(c.addr.ip())))
+			.map(|c| c.user_id)
+			.collect();
+		let reason = format!("Banned: {}", ban.reason);
+		self.bans.add(ban)?;
+		for user_id in matched {
+			if let Err(e) = self.kick_player(user_id, &reason).await {
+				log::warn!("Could not kick banned player {}: {:#}", user_id, e);
+			}
+		}
+		Ok(())
+	}
+
+	/// Disconnect a player and return its relay socket to the pool
+	/// Outstanding tokens are revoked so the player has to go through the master server again
+	pub async fn kick_player(&self, user_id: u64, reason: &str) -> Result<()> {
+		self.tokens.retain(|_, t| t.user_id != user_id);
+		let registered = self.conns.unregister(user_id);
+		let user_name = registered.clone().unwrap_or_default();
+		let conn = match self.conns.by_user(user_id) {
+			Some(c) => c,
+			None if registered.is_some() => {
+				// Only the token is revoked
+				self.events.emit(
+					Event::new(EventKind::Kicked, user_id, None)
+						.username(&user_name)
+						.detail(reason),
+				);
+				return Ok(());
+			}
+			None => return Err!(TitanfrontError::PlayerNotFound(user_id)),
+		};
+		self.events.emit(
+			relay_event(EventKind::Kicked, user_id, &conn)
+				.username(&user_name)
+				.detail(reason),
+		);
+		if let Some(lease) = self.conns.close(&conn) {
+			self.release_socket(lease);
+		}
+		log::info!("Kicked {} from {}", user_id, conn.addr);
+		Ok(())
+	}
+
+	/// Client a relay socket is bound to and the frontend socket that reaches it
+	/// Replies keep the connection alive while the client is quiet
+	fn client_of(&self, sender: &TUdpSocket) -> Option<(SocketAddr, TUdpSocket)> {
+		let conn = self.conns.by_relay(sender.id())?;
+		conn.touch_backend();
+		Some((conn.addr, conn.ingress.clone()))
+	}
+
+	/// Hand a reply to the migration waiting on a relay socket that has no client yet
+	fn answer_migration(&self, payload: &[u8], sender: &TUdpSocket) {
+		if let Some(waiting) = self.migrations.get(&sender.id()) {
+			// Anything past the first few replies is of no use to the handshake
+			let _ = waiting.try_send(payload.to_vec());
+		}
+	}
+
+	async fn relay_internal(&self, payload: &[u8], sender: &TUdpSocket) {
+		let (addr, ingress) = match self.client_of(sender) {
+			Some(c) => c,
+			None => {
+				self.answer_migration(payload, sender);
+				return;
+			}
+		};
+		match ingress.send_to(payload, addr).await {
+			Ok(_) => {
+				metrics::inc(&self.metrics.packets_to_client);
+				metrics::add(&self.metrics.bytes_to_client, payload.len() as u64);
+			}
+			Err(e) => log::warn!("Could not relay packet to {}: {}", addr, e),
+		}
+	}
+
+	/// Relay a batch of replies read from one relay socket
+	/// They all go to the same client so they are sent with one call
+	#[cfg(target_os = "linux")]
+	async fn relay_internal_batch(&self, batch: &mut RecvBatch, sender: &TUdpSocket) {
+		let (addr, ingress) = match self.client_of(sender) {
+			Some(c) => c,
+			None => {
+				for (payload, _) in batch.iter() {
+					self.answer_migration(payload, sender);
+				}
+				return;
+			}
+		};
+		let (packets, bytes) = (batch.received(), batch.bytes());
+		match ingress.send_batch(batch, addr).await {
+			Ok(()) => {
+				metrics::add(&self.metrics.packets_to_client, packets as u64);
+				metrics::add(&self.metrics.bytes_to_client, bytes as u64);
+			}
+			Err(e) => log::warn!("Could not relay packets to {}: {}", addr, e),
+		}
+	}
+
+	/// Move a connected player to another target server
+	/// Connects to the new target from a fresh relay socket and answers its challenge with the player's identity.
+	/// The client keeps talking to the same proxy address so it never has to reconnect.
+	/// The old relay socket is closed afterwards so the old target stops hearing from the player.
+	pub async fn migrate_player(
+		self: &Arc<Self>,
+		user_id: u64,
+		target: usize,
+		config: &SharedConfig,
+	) -> Result<()> {
+		let cfg = config.load_full();
+		let new_target = match cfg.target_servers.get(target) {
+			Some(t) => *t,
+			None => return Err!(TitanfrontError::BadTarget(target)),
+		};
+		let conn = match self.conns.by_user(user_id) {
+			Some(c) if c.is_authenticated() => c,
+			_ => return Err!(TitanfrontError::PlayerNotFound(user_id)),
+		};
+		if conn.target() == Some(new_target) {
+			return Ok(());
+		}
+		// The first packet is the connect request and the last the response that authenticated the player
+		let handshake = conn.handshake();
+		let (connect, mut response) = match (handshake.first(), handshake.last()) {
+			(Some(connect), Some(response)) if handshake.len() > 1 => {
+				let response = decrypt(response, &cfg)?;
+				ChallengeResponse::decode(&response)?;
+				(connect.clone(), response)
+			}
+			_ => return Err!(TitanfrontError::WrongMessage("connect response")),
+		};
+
+		let lease = if cfg.admins.contains(&user_id) {
+			self.pool.lease_admin()
+		} else {
+			self.pool.lease_player()
+		};
+		let lease = match lease {
+			Some(l) => l,
+			None => return Err!(TitanfrontError::NoRelaySocket()),
+		};
+		let sock = lease.sock().clone();
+		let (tx, mut rx) = mpsc::channel(4);
+		self.migrations.insert(sock.id(), tx);
+		let challenge = self
+			.request_challenge(&sock, &connect, new_target, &mut rx, &cfg)
+			.await;
+		self.migrations.remove(&sock.id());
+		let challenge = match challenge {
+			Ok(c) => c,
+			Err(e) => {
+				self.release_socket(lease);
+				return Err(e).context("Error connecting to new target");
+			}
+		};
+		// The challenge sits right after the connect header
+		response[CONNECT_HEADER.len()..CONNECT_HEADER.len() + 8].copy_from_slice(&challenge);
+		// The target may accept before the route is swapped
+		// The client keeps talking to its old target until then
+		self.conns.route_replies(&conn, sock.id());
+		if let Err(e) = sock.send_to(&encrypt(&response, &cfg), new_target).await {
+			self.conns.drop_replies(&conn, sock.id());
+			self.release_socket(lease);
+			return Err!(TitanfrontError::RelaySend(e)).context("Error answering new target");
+		}
+
+		match self.conns.reroute(&conn, lease, new_target) {
+			Ok((old, old_target)) => {
+				log::info!("Migrated {} to {}", user_id, new_target);
+				self.events.emit(
+					Event::new(EventKind::Migrated, user_id, Some(conn.addr))
+						.username(&self.conns.username(user_id))
+						.relay(sock.id(), new_target)
+						.detail(format!("From relay {} on {}", old.sock().id(), old_target)),
+				);
+				// Reusing the socket would hand the old target's traffic to the next player
+				self.retire_socket(self.pool.discard(old));
+				if let Err(e) = self.fill_pool(config).await {
+					log::error!("Could not replace relay socket: {:#}", e);
+				}
+				Ok(())
+			}
+			Err(lease) => {
+				// The player was cleaned up during the handshake
+				self.release_socket(lease);
+				Err!(TitanfrontError::PlayerNotFound(user_id))
+			}
+		}
+	}
+
+	/// Send a connect request to a target and wait for its challenge
+	async fn request_challenge(
+		&self,
+		sock: &TUdpSocket,
+		connect: &[u8],
+		target: SocketAddr,
+		replies: &mut mpsc::Receiver<Vec<u8>>,
+		config: &AppConfig,
+	) -> Result<[u8; 8]> {
+		if let Err(e) = sock.send_to(connect, target).await {
+			return Err!(TitanfrontError::RelaySend(e));
+		}
+		let deadline = tokio::time::Instant::now() + Duration::from_secs(config.handshake_timeout);
+		loop {
+			match tokio::time::timeout_at(deadline, replies.recv()).await {
+				Ok(Some(reply)) => {
+					match decrypt(&reply, config).and_then(|p| Challenge::decode(&p)) {
+						Ok(c) => return Ok(c.challenge),
+						Err(e) => log::debug!("Ignoring reply from {}: {}", target, e),
 					}
-				};
-				deletes.push(*sock);
-				bind.status = ConnStat::Blocked;
-				self.sockets.remove(&bind.sock);
-				self.available.write().await.push(bind.sock.clone())
+				}
+				_ => return Err!(TitanfrontError::MigrationTimeout(target)),
+			}
+		}
+	}
+
+	/// Index of the target server new players are sent to
+	pub fn get_join_target(&self) -> usize {
+		// Ideally writes should always beat reads but we can't really guarantee correctness here
+		// Connecting will take a long time for users so having a write beat reads is ideal
+		// Relaxed reads with Release for writes is as close as we can get
+		// ALSO ATOMICS BEHAVE DIFFERENTLY ON INTELx86-AMD64 AND ARM
+		// THIS CODE IS NOT GUARANTEED TO BE CONSISTENT ACROSS PLATFORMS
+		// SEE: https://doc.rust-lang.org/nomicon/atomics.html#hardware-reordering
+		self.join_target.load(Ordering::Relaxed)
+	}
+
+	/// Change the target server new players are sent to
+	/// Players that are already connected stay where they are
+	pub fn set_join_target(&self, target: usize, config: &AppConfig) -> Result<()> {
+		if target >= config.target_servers.len() {
+			return Err!(TitanfrontError::BadTarget(target));
+		}
+		// See the note in get_join_target about ordering across platforms
+		self.join_target.store(target, Ordering::Release);
+		log::info!(
+			"Join target set to {}: {}",
+			target,
+			config.target_servers[target]
+		);
+		Ok(())
+	}
+
+	/// Number of live binds on each target server
+	/// Indexed the same way as `AppConfig::target_servers`
+	pub fn target_loads(&self, config: &AppConfig) -> Vec<usize> {
+		let mut loads = vec![0; config.target_servers.len()];
+		for conn in self.conns.connections() {
+			if let Some(i) = conn
+				.target()
+				.and_then(|target| config.target_servers.iter().position(|t| *t == target))
+			{
+				loads[i] += 1;
+			}
+		}
+		loads
+	}
+
+	/// Start relaying replies from a relay socket back to its client
+	pub fn spawn_relay(self: &Arc<Self>, sock: TUdpSocket, config: SharedConfig) {
+		let id = sock.id();
+		let tables = self.clone();
+		let task = tokio::spawn(async move {
+			internal_handler(sock, config, tables)
+				.await
+				// Thread errors cannot propagate back to the main thread
+				// If they are unhandled by now they are fatal errors
+				.unwrap();
+		});
+		self.relay_tasks.insert(id, task);
+	}
+
+	/// Sweep dead connections in the background
+	pub fn spawn_cleanup(self: &Arc<Self>, config: SharedConfig) {
+		let tables = self.clone();
+		tokio::spawn(async move {
+			loop {
+				// Read every round so a reload takes effect at the next sweep
+				let every = config.load().cleanup_interval;
+				tokio::time::sleep(Duration::from_secs(every)).await;
+				tables.cleanup_dead_connections(&config.load_full()).await;
+			}
+		});
+	}
+
+	/// Give a relay socket back to the pool
+	/// Sockets above the pool target are closed instead
+	fn release_socket(&self, lease: Lease) {
+		if let Some(sock) = self.pool.release(lease) {
+			self.retire_socket(sock);
+		}
+	}
+
+	/// Close a relay socket for good
+	fn retire_socket(&self, sock: TUdpSocket) {
+		if let Some((_, task)) = self.relay_tasks.remove(&sock.id()) {
+			// The socket closes once the receive task drops its handle
+			task.abort();
+		}
+	}
+
+	/// Grow or shrink the relay socket pool
+	/// Sockets that are in use are closed when their player leaves
+	pub async fn resize_pool(
+		self: &Arc<Self>,
+		players: usize,
+		admins: usize,
+		config: &SharedConfig,
+	) -> Result<()> {
+		let current = self.pool.size();
+		for (admin, size) in [(false, players), (true, admins)] {
+			for sock in self.pool.set_target(admin, size) {
+				self.retire_socket(sock);
 			}
 		}
-		// Delete has to go outside the scope of the Bind's borrow or it might race
-		for delete in deletes {
-			let kv = self.ips.remove(&delete);
-			// Check to make sure we are not deleting in use IPs
-			assert!(kv.unwrap().1.status == ConnStat::Blocked);
+		self.fill_pool(config).await?;
+		if current != players + admins {
+			log::info!(
+				"Relay pool resized from {} to {} sockets",
+				current,
+				self.pool.size()
+			);
+		}
+		Ok(())
+	}
+
+	/// Bind sockets until the pool is back at its target size
+	async fn fill_pool(self: &Arc<Self>, config: &SharedConfig) -> Result<()> {
+		let relay_address = config.load().relay_address.clone();
+		for admin in [false, true] {
+			for _ in 0..self.pool.shortfall(admin) {
+				let id = self.next_socket_id.fetch_add(1, Ordering::Relaxed);
+				let sock = TUdpSocket::bind(&relay_address, id)
+					.await
+					.map_err(TitanfrontError::RelayBind)
+					.context("Error growing relay socket pool")?;
+				self.spawn_relay(sock.clone(), config.clone());
+				self.pool.add(admin, sock);
+			}
+		}
+		Ok(())
+	}
+
+	/// Relay sockets waiting for a player
+	pub fn free_sockets(&self) -> usize {
+		self.pool.free()
+	}
+
+	/// Refuse new players while existing ones finish their match
+	pub fn start_draining(&self) {
+		self.draining.store(true, Ordering::Release);
+	}
+
+	pub fn is_draining(&self) -> bool {
+		self.draining.load(Ordering::Acquire)
+	}
+
+	/// Binds that have completed the connect handshake
+	pub fn authenticated_count(&self) -> usize {
+		self.conns
+			.connections()
+			.iter()
+			.filter(|c| c.is_authenticated())
+			.count()
+	}
+
+	/// Whether a player has a connection open
+	pub fn is_connected(&self, user_id: u64) -> bool {
+		self.conns.by_user(user_id).is_some()
+	}
+
+	pub fn get_player_count(&self) -> u64 {
+		self.conns.len() as u64
+	}
+
+	pub async fn cleanup_dead_connections(&self, config: &AppConfig) {
+		if let Err(e) = self.bans.purge_expired() {
+			log::error!("Could not purge expired bans: {:#}", e);
+		}
+		let ttl = Duration::from_secs(config.token_ttl);
+		let before = self.tokens.len();
+		self.tokens.retain(|_, t| t.is_live(ttl));
+		// Used tokens are removed on connect so anything swept here was never used
+		let expired = before.saturating_sub(self.tokens.len());
+		if expired > 0 {
+			log::info!("Expired {} unused tokens", expired);
+			metrics::add(&self.metrics.tokens_expired, expired as u64);
+		}
+		// Players the master server sent that never showed up
+		let holders: HashSet<u64> = self.tokens.iter().map(|t| t.user_id).collect();
+		let absent = self.conns.unregister_absent(|id| holders.contains(&id));
+		if !absent.is_empty() {
+			log::info!("Forgot {} players that never connected", absent.len());
+		}
+		self.limiter.sweep(config);
+		self.policy.sweep(self, config);
+		let timeouts = Timeouts::new(config);
+		for conn in self.conns.connections() {
+			let (client, backend) = conn.idle();
+			let kind = match timeouts.expired(conn.is_connecting(), client, backend) {
+				Some(k) => k,
+				None => continue,
+			};
+			let detail = match kind {
+				EventKind::HandshakeTimeout => {
+					format!("Handshake not completed after {}s", client.as_secs())
+				}
+				_ => format!(
+					"Idle for {}s. Server quiet for {}s",
+					client.as_secs(),
+					backend.as_secs()
+				),
+			};
+			// The event is built first since closing takes the relay details
+			let event = relay_event(kind, conn.user_id, &conn)
+				.username(&self.conns.username(conn.user_id))
+				.detail(detail);
+			// A kick or the handshake may have changed it in the meantime
+			let lease = match kind {
+				EventKind::HandshakeTimeout => self.conns.reject(&conn),
+				_ => self.conns.close(&conn),
+			};
+			let lease = match lease {
+				Some(l) => l,
+				None => continue,
+			};
+			self.events.emit(event);
+			self.release_socket(lease);
+			if kind == EventKind::HandshakeTimeout {
+				log::info!("Reclaimed relay from stuck handshake by {}", conn.addr);
+				metrics::inc(&self.metrics.handshake_timeouts);
+				// The player may retry with the same token
+				continue;
+			}
+			// The player may already be back on a new address
+			if self.conns.by_user(conn.user_id).is_none() {
+				if let Some(name) = self.conns.unregister(conn.user_id) {
+					log::info!("Dropped idle player {}:{}", conn.user_id, name);
+				}
+			}
 		}
 	}
 }
 
-pub async fn external_handler<'a>(
+pub async fn external_handler(
 	socket: TUdpSocket,
-	config: Arc<AppConfig>,
+	config: SharedConfig,
 	routecfg: Arc<Router>,
 ) -> Result<()> {
+	// The auth server and buffer size only change on restart
+	let startup = config.load_full();
 	let mut auth_ips: HashSet<IpAddr> = HashSet::new();
-	let auth_addr = config
+	let auth_addr = startup
         .auth_server
         .replace("http://", "")
         .replace("https://", "")
@@ -320,7 +1042,7 @@ pub async fn external_handler<'a>(
 		}
 		Err(e) => {
 			log::warn!("Error: {}", e);
-			for addr in config
+			for addr in startup
 				.auth_server
 				.replace("http://", "")
 				.replace("https://", "")
@@ -332,43 +1054,30 @@ pub async fn external_handler<'a>(
 		}
 	}
 
-	let auth_ips_pointer = Arc::new(auth_ips);
-	let router_pointer = Arc::new(routecfg);
+	let auth_ips = Arc::new(auth_ips);
+	#[cfg(target_os = "linux")]
+	if startup.udp_batch_size > 1 {
+		let mut batch = RecvBatch::new(startup.udp_batch_size, startup.receive_buf_size);
+		loop {
+			if let Err(e) = socket.recv_batch(&mut batch).await {
+				log::error!("Issue receiving from external socket");
+				return Err!(TitanfrontError::SwitchReceive(e))
+					.context("Error receiving in external handler");
+			}
+			for (payload, addr) in batch.iter() {
+				dispatch_external(&routecfg, payload, addr, &socket, &config, &auth_ips).await;
+			}
+		}
+	}
+	#[cfg(not(target_os = "linux"))]
+	if startup.udp_batch_size > 1 {
+		log::warn!("UDP batching is only supported on Linux");
+	}
+	let mut buf: Vec<u8> = vec![0; startup.receive_buf_size];
 	loop {
-		let mut buf: Vec<u8> = vec![0; config.receive_buf_size];
 		match socket.recv_from(&mut buf).await {
 			Ok((rl, addr)) => {
-				let cnf = config.clone();
-				let msg = buf.clone();
-				let insoc = socket.clone();
-				let auth_server_ips = auth_ips_pointer.clone();
-				let router = router_pointer.clone();
-				tokio::spawn(async move {
-					router
-						.relay_external(&msg[..rl].to_vec(), &addr, &cnf)
-						.await;
-					if auth_server_ips.clone().contains(&addr.ip()) {
-						let mut challenge = Vec::from(CHALLENGE_AUTH_SERVER_MESSAGE_LEADER);
-
-						log::debug!("buf: {:?}", &msg[..rl]);
-						let ptext = decrypt(&msg[..rl], &cnf);
-						log::debug!("ptext: {:?}", ptext);
-						let uid = &mut ptext[13..21].to_owned();
-						log::debug!("uid: {:?}", uid);
-						challenge.append(uid);
-						challenge.extend_from_slice(&CHALLENGE_AUTH_SERVER_MESSAGE_TRAILER);
-						let ctext = encrypt(&challenge, &cnf);
-						match insoc.send_to(&ctext, addr).await {
-							Ok(_) => {
-								log::debug!("Response: {:?}", ctext);
-								log::info!("Responded to auth server UDP query");
-							}
-							Err(e) => {
-								log::warn!("Could not respond to auth server UDP query: {}", e);
-							}
-						}
-					}
-				});
+				dispatch_external(&routecfg, &buf[..rl], addr, &socket, &config, &auth_ips).await;
 			}
 			Err(e) => {
 				log::error!("Issue receiving from external socket");
@@ -379,17 +1088,98 @@ pub async fn external_handler<'a>(
 	}
 }
 
+/// Handle one datagram from a player
+/// Authenticated players are relayed straight from the receive loop.
+/// Anything else is copied into a pooled buffer and handled in its own task.
+async fn dispatch_external(
+	router: &Arc<Router>,
+	payload: &[u8],
+	addr: SocketAddr,
+	ingress: &TUdpSocket,
+	config: &SharedConfig,
+	auth_ips: &Arc<HashSet<IpAddr>>,
+) {
+	if router.relay_established(payload, &addr).await {
+		return;
+	}
+	let cnf = config.load_full();
+	// Dropped before spawning so floods cost as little as possible
+	if !router.admit(&addr, &cnf) {
+		return;
+	}
+	let mut msg = router.buffers.take();
+	msg.extend_from_slice(payload);
+	let insoc = ingress.clone();
+	// Reachability probes are not players so they skip the cookie, events and metrics
+	let from_auth_server = auth_ips.contains(&addr.ip());
+	let router = router.clone();
+	tokio::spawn(async move {
+		if from_auth_server {
+			answer_auth_server(&msg, &addr, &insoc, &cnf).await;
+		} else {
+			router.relay_external(&msg, &addr, &insoc, &cnf).await;
+		}
+		router.buffers.give(msg);
+	});
+}
+
+/// The auth server checks the server is reachable with a connect request
+async fn answer_auth_server(
+	payload: &[u8],
+	addr: &SocketAddr,
+	ingress: &TUdpSocket,
+	config: &AppConfig,
+) {
+	log::debug!("buf: {:?}", payload);
+	let uid = match decrypt(payload, config).and_then(|p| ConnectRequest::decode(&p)) {
+		Ok(req) => req.user_id,
+		Err(e) => {
+			log::warn!("Ignoring auth server UDP query: {}", e);
+			return;
+		}
+	};
+	log::debug!("uid: {:?}", uid);
+	// The user ID is echoed as the challenge
+	let challenge = Challenge {
+		challenge: uid.to_le_bytes(),
+	}
+	.encode();
+	let ctext = encrypt(&challenge, config);
+	match ingress.send_to(&ctext, *addr).await {
+		Ok(_) => {
+			log::debug!("Response: {:?}", ctext);
+			log::info!("Responded to auth server UDP query");
+		}
+		Err(e) => {
+			log::warn!("Could not respond to auth server UDP query: {}", e);
+		}
+	}
+}
+
 pub async fn internal_handler(
 	socket: TUdpSocket,
-	config: Arc<AppConfig>,
+	config: SharedConfig,
 	routecfg: Arc<Router>,
-	proxy: TUdpSocket,
 ) -> Result<()> {
+	// Buffer and batch sizes only change on restart
+	let startup = config.load_full();
+	#[cfg(target_os = "linux")]
+	if startup.udp_batch_size > 1 {
+		let mut batch = RecvBatch::new(startup.udp_batch_size, startup.receive_buf_size);
+		loop {
+			if let Err(e) = socket.recv_batch(&mut batch).await {
+				log::error!("Issue receiving from internal socket");
+				return Err!(TitanfrontError::SwitchReceive(e))
+					.context("Error receiving in internal handler");
+			}
+			routecfg.relay_internal_batch(&mut batch, &socket).await;
+		}
+	}
+	let mut buf: Vec<u8> = vec![0; startup.receive_buf_size];
 	loop {
-		let mut buf: Vec<u8> = vec![0; config.receive_buf_size];
 		match socket.recv_from(&mut buf).await {
 			Ok((rl, _)) => {
-				routecfg.relay_internal(&buf[..rl], &socket, &proxy).await;
+				routecfg.relay_internal(&buf[..rl], &socket).await;
 			}
 			Err(e) => {
 				log::error!("Issue receiving from internal socket");
@@ -399,3 +1189,592 @@ pub async fn internal_handler(
 		}
 	}
 }
+
+#[cfg(test)]
+impl Router {
+	/// Router over `sockets` with no ban file or event log
+	pub fn for_tests(sockets: &[TUdpSocket], config: &AppConfig) -> Router {
+		Router::new(
+			sockets,
+			config,
+			BanList::load("").unwrap(),
+			EventLog::default(),
+		)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	use std::env;
+
+	use {
+		arc_swap::ArcSwap,
+		tokio::{net::UdpSocket, time::timeout},
+	};
+
+	const CLIENTS: usize = 16;
+	/// Packets each client sends
+	const PACKETS: usize = 20_000;
+	/// Packets each client keeps in flight
+	const WINDOW: usize = 8;
+	const PAYLOAD: usize = 128;
+	const WAIT: Duration = Duration::from_millis(500);
+	/// Time without an echo before a client assumes its window was lost
+	const STALL: Duration = Duration::from_millis(50);
+
+	/// Game servers that send every packet straight back
+	async fn echo_target() -> SocketAddr {
+		let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = sock.local_addr().unwrap();
+		tokio::spawn(async move {
+			let mut buf = [0; 2048];
+			while let Ok((len, from)) = sock.recv_from(&mut buf).await {
+				let _ = sock.send_to(&buf[..len], from).await;
+			}
+		});
+		addr
+	}
+
+	fn bench_config(target: SocketAddr, batch: usize) -> AppConfig {
+		AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\"]\n\
+			player_count = {}\n\
+			udp_batch_size = {}\n",
+			target, CLIENTS, batch
+		))
+	}
+
+	#[test]
+	fn timeouts_by_state() {
+		let timeouts = Timeouts {
+			handshake: Duration::from_secs(10),
+			idle: Duration::from_secs(5),
+			backend_grace: Duration::from_secs(10),
+		};
+		let ms = Duration::from_millis;
+		let (handshake, timeout) = (Some(EventKind::HandshakeTimeout), Some(EventKind::Timeout));
+		// Connecting, client idle, backend idle, expected
+		let cases = [
+			// The handshake clock runs from the first connect whatever the server does
+			(true, ms(9_999), ms(9_999), None),
+			(true, ms(10_001), ms(10_001), handshake),
+			(true, ms(10_001), ms(0), handshake),
+			// Past the idle timeout but still within the handshake timeout
+			(true, ms(6_000), ms(6_000), None),
+			// Both sides quiet for the same time
+			(false, ms(4_999), ms(4_999), None),
+			(false, ms(5_001), ms(5_001), timeout),
+			// Traffic either way keeps the connection
+			(false, ms(60_000), ms(100), None),
+			(false, ms(100), ms(60_000), None),
+			// The server went quiet first so the grace is added
+			(false, ms(14_999), ms(20_000), None),
+			(false, ms(15_001), ms(20_000), timeout),
+			// The client went quiet first so there is no grace
+			(false, ms(20_000), ms(4_999), None),
+			(false, ms(20_000), ms(5_001), timeout),
+			// Authenticated connections never hit the handshake timeout
+			(false, ms(4_000), ms(4_000), None),
+		];
+		for (connecting, client, backend, expected) in cases {
+			assert_eq!(
+				timeouts.expired(connecting, client, backend),
+				expected,
+				"connecting {} client {:?} backend {:?}",
+				connecting,
+				client,
+				backend
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn admins_get_tokens_when_player_slots_are_full() {
+		let config = AppConfig::for_tests("admins = [1]\n");
+		let socks = [TUdpSocket::bind("127.0.0.1:0", 0).await.unwrap()];
+		// The only socket is the admin's
+		let router = Router::for_tests(&socks, &config);
+		let token =
+			|id: u64| router.add_token(format!("token{}", id), id, String::from("pilot"), &config);
+		assert!(token(2).await.is_err());
+		assert!(token(1).await.is_ok());
+	}
+
+	#[test]
+	fn decrypt_rejects_bad_packets() {
+		let config = AppConfig::for_tests("");
+		let ctext = encrypt(&ConnectRequest { user_id: 7 }.encode(), &config);
+		assert_eq!(
+			ConnectRequest::decode(&decrypt(&ctext, &config).unwrap()).unwrap(),
+			ConnectRequest { user_id: 7 }
+		);
+		for len in 0..ENCRYPTION_OVERHEAD {
+			assert!(matches!(
+				decrypt(&ctext[..len], &config),
+				Err(TitanfrontError::Truncated(_))
+			));
+		}
+		// An empty packet with a valid tag is fine
+		assert!(decrypt(&encrypt(&[], &config), &config).unwrap().is_empty());
+		let mut tampered = ctext.clone();
+		tampered[12] ^= 1;
+		assert!(matches!(
+			decrypt(&tampered, &config),
+			Err(TitanfrontError::Decrypt())
+		));
+		// Cutting the ciphertext breaks the tag as well
+		assert!(matches!(
+			decrypt(&ctext[..ctext.len() - 1], &config),
+			Err(TitanfrontError::Decrypt())
+		));
+	}
+
+	#[test]
+	fn truncated_connects_are_errors() {
+		let config = AppConfig::for_tests("");
+		// Properly encrypted but cut short before the user ID ends
+		let plain = ConnectRequest { user_id: 7 }.encode();
+		let ctext = encrypt(&plain[..plain.len() - 1], &config);
+		assert!(matches!(
+			decrypt(&ctext, &config).and_then(|p| ConnectRequest::decode(&p)),
+			Err(TitanfrontError::Truncated(_))
+		));
+		let ctext = encrypt(&packet::CONNECT_HEADER[..5], &config);
+		assert!(matches!(
+			decrypt(&ctext, &config).and_then(|p| ConnectRequest::decode(&p)),
+			Err(TitanfrontError::WrongMessage(_))
+		));
+	}
+
+	/// The master server's reachability probe gets its user ID back even with cookies on
+	#[tokio::test]
+	async fn auth_probes_skip_cookies() {
+		let config = AppConfig::for_tests("connect_cookies = true\n");
+		let router = Arc::new(Router::for_tests(&[], &config));
+		let shared: SharedConfig = Arc::new(ArcSwap::from_pointee(config));
+		let ingress = TUdpSocket::bind("127.0.0.1:0", usize::MAX).await.unwrap();
+		let probe = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let from = probe.local_addr().unwrap();
+		let auth_ips = Arc::new(HashSet::from([from.ip()]));
+		let request = encrypt(&ConnectRequest { user_id: 12345 }.encode(), &shared.load());
+		dispatch_external(&router, &request, from, &ingress, &shared, &auth_ips).await;
+		let mut buf = [0; 2048];
+		let len = timeout(WAIT, probe.recv(&mut buf))
+			.await
+			.expect("No answer to the probe")
+			.unwrap();
+		let reply = decrypt(&buf[..len], &shared.load())
+			.and_then(|p| Challenge::decode(&p))
+			.unwrap();
+		assert_eq!(reply.challenge, 12345u64.to_le_bytes());
+		assert_eq!(router.metrics().connect_attempts.load(Ordering::Relaxed), 0);
+	}
+
+	/// Players stay while their server talks and the sweep runs at the configured interval
+	#[tokio::test]
+	async fn backend_traffic_keeps_players() {
+		let conf = AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\"]\n\
+			player_count = 3\n\
+			idle_timeout = 1\n\
+			backend_grace = 3\n\
+			cleanup_interval = 1\n",
+			echo_target().await
+		));
+		let (router, shared, proxy) = start_proxy(conf).await;
+		router.spawn_cleanup(shared.clone());
+
+		// The handshake is echoed so every client went quiet after its server
+		let _clients = [
+			connect(proxy, 1, &shared.load()).await,
+			connect(proxy, 2, &shared.load()).await,
+			connect(proxy, 3, &shared.load()).await,
+		];
+		let conn = |id: u64| router.conns.by_user(id);
+		// Player 2's server goes quiet first and gets the grace
+		// Traffic in the same millisecond counts as the client going quiet first
+		tokio::time::sleep(Duration::from_millis(20)).await;
+		conn(2).unwrap().touch();
+		// Player 1's server keeps sending while the client is quiet
+		for _ in 0..10 {
+			conn(1).unwrap().touch_backend();
+			tokio::time::sleep(Duration::from_millis(250)).await;
+		}
+		assert!(conn(1).is_some(), "Reaped while the server was sending");
+		assert!(conn(2).is_some(), "Reaped within the backend grace");
+		assert!(conn(3).is_none(), "Not reaped at the sweep interval");
+	}
+
+	/// Game server that challenges connects and echoes everything else
+	async fn game_target() -> SocketAddr {
+		let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = sock.local_addr().unwrap();
+		let config = AppConfig::for_tests("");
+		tokio::spawn(async move {
+			let mut buf = [0; 2048];
+			while let Ok((len, from)) = sock.recv_from(&mut buf).await {
+				let plain = decrypt(&buf[..len], &config).unwrap_or_default();
+				// A connect response decodes as a connect request too
+				let reply = match ChallengeResponse::decode(&plain) {
+					Ok(_) => buf[..len].to_vec(),
+					Err(_) if ConnectRequest::decode(&plain).is_ok() => {
+						encrypt(&Challenge { challenge: [7; 8] }.encode(), &config)
+					}
+					Err(_) => buf[..len].to_vec(),
+				};
+				let _ = sock.send_to(&reply, from).await;
+			}
+		});
+		addr
+	}
+
+	/// Runs on several threads so the new target can answer while the migration finishes
+	#[tokio::test(flavor = "multi_thread")]
+	async fn migrations_hand_over_the_handshake() {
+		let (first, second) = (game_target().await, game_target().await);
+		let conf = AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\", \"{}\"]\n\
+			player_count = 2\n",
+			first, second
+		));
+		let (router, shared, proxy) = start_proxy(conf).await;
+		let client = connect(proxy, 1, &shared.load()).await;
+		let conn = router.conns.by_user(1).unwrap();
+		assert_eq!(conn.target(), Some(first));
+		router.migrate_player(1, 1, &shared).await.unwrap();
+		assert_eq!(conn.target(), Some(second));
+		let mut buf = [0; 2048];
+		// The new target's answer to the handshake reaches the client
+		let len = timeout(WAIT, client.recv(&mut buf))
+			.await
+			.expect("Accept from the new target was dropped")
+			.unwrap();
+		let accept = decrypt(&buf[..len], &shared.load())
+			.and_then(|p| ChallengeResponse::decode(&p))
+			.unwrap();
+		assert_eq!(accept.challenge, [7; 8]);
+		assert_eq!(accept.user_id, 1);
+		// Game traffic follows the player
+		client.send(b"after").await.unwrap();
+		let len = timeout(WAIT, client.recv(&mut buf)).await.unwrap().unwrap();
+		assert_eq!(&buf[..len], b"after");
+		// The old relay socket is retired and replaced
+		assert_eq!(router.pool.free(), 1);
+		// Unknown targets and players are errors
+		assert!(router.migrate_player(1, 2, &shared).await.is_err());
+		assert!(router.migrate_player(2, 0, &shared).await.is_err());
+	}
+
+	/// Registrations go once their tokens lapse unless the player is connected
+	#[tokio::test]
+	async fn absent_players_are_forgotten() {
+		let conf = AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\"]\n\
+			player_count = 2\n",
+			echo_target().await
+		));
+		let (router, shared, proxy) = start_proxy(conf).await;
+		let config = shared.load_full();
+		let _client = connect(proxy, 1, &config).await;
+		router
+			.add_token(String::from("token"), 2, String::from("late"), &config)
+			.await
+			.unwrap();
+		router.cleanup_dead_connections(&config).await;
+		assert!(router.conns.player(1).is_some());
+		assert!(router.conns.player(2).is_some());
+		router.tokens.get_mut("token").unwrap().issued -= Duration::from_secs(config.token_ttl);
+		router.cleanup_dead_connections(&config).await;
+		assert!(
+			router.conns.player(1).is_some(),
+			"Connected player was forgotten"
+		);
+		assert!(router.conns.player(2).is_none());
+		assert!(router.conns.players().iter().all(|p| p.user_id == 1));
+	}
+
+	#[tokio::test]
+	async fn tokens_are_capped_and_expire() {
+		let config = AppConfig::for_tests("max_tokens_per_user = 2\n");
+		let socks = [TUdpSocket::bind("127.0.0.1:0", 0).await.unwrap()];
+		let router = Router::for_tests(&socks, &config);
+		for token in ["first", "second", "third"] {
+			router
+				.add_token(String::from(token), 1, String::from("pilot"), &config)
+				.await
+				.unwrap();
+		}
+		router
+			.add_token(String::from("other"), 2, String::from("pilot"), &config)
+			.await
+			.unwrap();
+		// The oldest token is revoked to make room
+		assert!(!router.tokens.contains_key("first"));
+		assert!(router.tokens.contains_key("second"));
+		assert!(router.tokens.contains_key("third"));
+		assert!(router.holds_token(1, &config));
+		let ttl = Duration::from_secs(config.token_ttl);
+		for token in ["second", "third"] {
+			router.tokens.get_mut(token).unwrap().issued -= ttl;
+		}
+		assert!(!router.holds_token(1, &config));
+		router.cleanup_dead_connections(&config).await;
+		assert_eq!(router.metrics().tokens_expired.load(Ordering::Relaxed), 2);
+		assert!(router.tokens.contains_key("other"));
+		assert!(router.holds_token(2, &config));
+	}
+
+	/// With central auth a relay socket is only leased to a user holding a live token
+	#[tokio::test]
+	async fn tokens_are_single_use() {
+		let conf = AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\"]\n\
+			player_count = 2\n\
+			auth_enabled = true\n",
+			echo_target().await
+		));
+		let (router, shared, proxy) = start_proxy(conf).await;
+		let config = shared.load_full();
+		let add = |token: &str, id: u64| {
+			router.add_token(String::from(token), id, String::from("pilot"), &config)
+		};
+		// Nobody sent this user
+		assert!(handshake(proxy, 1, "", &config).await.is_none());
+		assert_eq!(router.conns.len(), 0);
+		add("token", 1).await.unwrap();
+		let _client = handshake(proxy, 1, "token", &config).await.unwrap();
+		assert!(router.tokens.is_empty());
+		assert!(router.is_connected(1));
+		// The used token cannot be replayed from somewhere else
+		assert!(handshake(proxy, 1, "token", &config).await.is_none());
+		// Nor can an expired one
+		add("stale", 2).await.unwrap();
+		router.tokens.get_mut("stale").unwrap().issued -= Duration::from_secs(config.token_ttl);
+		assert!(handshake(proxy, 2, "stale", &config).await.is_none());
+		assert_eq!(router.conns.len(), 1);
+		assert_eq!(router.authenticated_count(), 1);
+	}
+
+	/// A stale cookie gets a new one for the player's real ID
+	#[tokio::test]
+	async fn stale_cookies_are_replaced() {
+		let conf = AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\"]\n\
+			player_count = 1\n\
+			connect_cookies = true\n",
+			echo_target().await
+		));
+		let (router, shared, proxy) = start_proxy(conf).await;
+		let config = shared.load_full();
+		let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		client.connect(proxy).await.unwrap();
+		let mut buf = [0; 2048];
+		let mut echo = ChallengeResponse {
+			challenge: [9; 8],
+			user_id: 55,
+			username: String::from("pilot"),
+			token: String::new(),
+		};
+		client
+			.send(&encrypt(&echo.encode(), &config))
+			.await
+			.unwrap();
+		let len = timeout(WAIT, client.recv(&mut buf)).await.unwrap().unwrap();
+		let cookie = decrypt(&buf[..len], &config)
+			.and_then(|p| Challenge::decode(&p))
+			.unwrap();
+		assert_eq!(router.conns.len(), 0);
+		echo.challenge = cookie.challenge;
+		client
+			.send(&encrypt(&echo.encode(), &config))
+			.await
+			.unwrap();
+		timeout(WAIT, client.recv(&mut buf))
+			.await
+			.expect("The new cookie was not accepted")
+			.unwrap();
+		let conn = router.conns.get(&client.local_addr().unwrap()).unwrap();
+		assert_eq!(conn.user_id, 55);
+	}
+
+	/// Relay sockets, receive tasks and a player listener for a config
+	/// Returns the listener's address
+	async fn start_proxy(conf: AppConfig) -> (Arc<Router>, SharedConfig, SocketAddr) {
+		let mut relays = Vec::new();
+		for i in 0..conf.player_count {
+			relays.push(TUdpSocket::bind(&conf.relay_address, i).await.unwrap());
+		}
+		let router = Arc::new(Router::for_tests(&relays, &conf));
+		let shared: SharedConfig = Arc::new(ArcSwap::from_pointee(conf));
+		for sock in relays {
+			router.spawn_relay(sock, shared.clone());
+		}
+		let listener = TUdpSocket::bind("127.0.0.1:0", usize::MAX).await.unwrap();
+		let proxy = listener.local_addr().unwrap();
+		tokio::spawn(external_handler(listener, shared.clone(), router.clone()));
+		(router, shared, proxy)
+	}
+
+	/// The handshake has to carry the ID the first connect was admitted with
+	#[tokio::test]
+	async fn handshakes_keep_their_user_id() {
+		let conf = AppConfig::for_tests(&format!(
+			"target_servers = [\"{}\"]\n\
+			player_count = 2\n",
+			echo_target().await
+		));
+		let (router, shared, proxy) = start_proxy(conf).await;
+		let player = connect(proxy, 1, &shared.load()).await;
+		let spoofer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		spoofer.connect(proxy).await.unwrap();
+		let mut buf = [0; 2048];
+		// Connecting as player 1 does not take over their connection
+		let request = ConnectRequest { user_id: 1 }.encode();
+		spoofer
+			.send(&encrypt(&request, &shared.load()))
+			.await
+			.unwrap();
+		timeout(WAIT, spoofer.recv(&mut buf))
+			.await
+			.unwrap()
+			.unwrap();
+		let conn = router.conns.by_user(1).unwrap();
+		assert_eq!(conn.addr, player.local_addr().unwrap());
+		// Nor does finishing as someone else
+		let response = ChallengeResponse {
+			challenge: [0; 8],
+			user_id: 2,
+			username: String::from("spoofer"),
+			token: String::new(),
+		}
+		.encode();
+		spoofer
+			.send(&encrypt(&response, &shared.load()))
+			.await
+			.unwrap();
+		assert!(timeout(Duration::from_millis(200), spoofer.recv(&mut buf))
+			.await
+			.is_err());
+		assert!(router.conns.get(&spoofer.local_addr().unwrap()).is_none());
+		assert!(router.conns.by_user(2).is_none());
+		assert!(Arc::ptr_eq(&router.conns.by_user(1).unwrap(), &conn));
+		assert_eq!(router.metrics().spoof_rejections.load(Ordering::Relaxed), 1);
+		assert_eq!(router.conns.username(1), "bench1");
+	}
+
+	/// Complete the connect handshake so the client has an authenticated bind
+	async fn connect(proxy: SocketAddr, user_id: u64, config: &AppConfig) -> UdpSocket {
+		handshake(proxy, user_id, "", config)
+			.await
+			.expect("No echo during the handshake")
+	}
+
+	/// Connect with a token
+	/// None when the proxy stops relaying partway
+	async fn handshake(
+		proxy: SocketAddr,
+		user_id: u64,
+		token: &str,
+		config: &AppConfig,
+	) -> Option<UdpSocket> {
+		let sock = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		sock.connect(proxy).await.unwrap();
+		let mut buf = [0; 2048];
+		let request = ConnectRequest { user_id }.encode();
+		let response = ChallengeResponse {
+			challenge: [0; 8],
+			user_id,
+			username: format!("bench{}", user_id),
+			token: String::from(token),
+		}
+		.encode();
+		for plain in [request, response] {
+			sock.send(&encrypt(&plain, config)).await.unwrap();
+			timeout(WAIT, sock.recv(&mut buf)).await.ok()?.unwrap();
+		}
+		Some(sock)
+	}
+
+	/// Send packets keeping a window in flight
+	/// Returns the echoes received and when the last one arrived
+	async fn blast(sock: UdpSocket) -> (usize, Instant) {
+		let payload = [0x5A; PAYLOAD];
+		let mut buf = [0; 2048];
+		let (mut sent, mut echoed) = (0, 0);
+		let mut last = Instant::now();
+		while sent < WINDOW {
+			sock.send(&payload).await.unwrap();
+			sent += 1;
+		}
+		while echoed < PACKETS {
+			match timeout(STALL, sock.recv(&mut buf)).await {
+				Ok(Ok(_)) => {
+					echoed += 1;
+					last = Instant::now();
+					if sent < PACKETS {
+						sock.send(&payload).await.unwrap();
+						sent += 1;
+					}
+				}
+				// Socket buffers overflowed and took the whole window with them
+				Err(_) if sent < PACKETS => {
+					for _ in 0..WINDOW.min(PACKETS - sent) {
+						sock.send(&payload).await.unwrap();
+						sent += 1;
+					}
+				}
+				_ => break,
+			}
+		}
+		(echoed, last)
+	}
+
+	/// Packets per second relayed between players and echoing game servers
+	/// Run with `cargo test --release -- --ignored --nocapture bench_relay`
+	/// Set BENCH_BATCH_SIZE to compare recvmmsg batching
+	#[test]
+	#[ignore]
+	fn bench_relay() {
+		let batch = env::var("BENCH_BATCH_SIZE")
+			.ok()
+			.and_then(|b| b.parse().ok())
+			.unwrap_or(1);
+		let rt = tokio::runtime::Runtime::new().unwrap();
+		rt.block_on(async {
+			let conf = bench_config(echo_target().await, batch);
+			let (router, shared, proxy) = start_proxy(conf).await;
+
+			let mut clients = Vec::new();
+			for user_id in 0..CLIENTS {
+				clients.push(connect(proxy, user_id as u64, &shared.load()).await);
+			}
+			assert_eq!(router.authenticated_count(), CLIENTS);
+
+			let start = Instant::now();
+			let runs: Vec<_> = clients
+				.into_iter()
+				.map(|sock| tokio::spawn(blast(sock)))
+				.collect();
+			let (mut echoed, mut end) = (0, start);
+			for run in runs {
+				let (n, last) = run.await.unwrap();
+				echoed += n;
+				end = end.max(last);
+			}
+			let secs = (end - start).as_secs_f64();
+			// Every echo went through the router twice
+			println!(
+				"batch {}: {} clients relayed {} packets in {:.2}s, {:.0} packets/s, {} lost",
+				batch,
+				CLIENTS,
+				echoed * 2,
+				secs,
+				(echoed * 2) as f64 / secs,
+				CLIENTS * PACKETS - echoed
+			);
+			assert!(echoed > 0);
+		});
+	}
+}
@@ -1,12 +1,40 @@
-use std::{hash::Hash, io, net::SocketAddr, sync::Arc};
+use std::{
+	hash::Hash,
+	io,
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+};
 
-use tokio::net::{ToSocketAddrs, UdpSocket};
+#[cfg(target_os = "linux")]
+use std::{
+	mem,
+	net::{Ipv4Addr, Ipv6Addr, SocketAddrV6},
+	os::unix::io::{AsRawFd, RawFd},
+	ptr,
+};
+
+use {
+	socket2::{Domain, Protocol, Socket, Type},
+	tokio::net::{ToSocketAddrs, UdpSocket},
+};
+
+#[cfg(target_os = "linux")]
+use tokio::io::Interest;
 
 #[derive(Debug, Clone)]
 /// Hashable UDP socket
+/// Dual-stack sockets hand out IPv4 peers as plain IPv4 addresses
 pub struct TUdpSocket {
 	sock: Arc<UdpSocket>,
 	id: usize,
+	/// IPv4 peers have to be addressed as mapped IPv6 addresses
+	v6: bool,
+}
+
+/// IPv4 peers of a dual-stack socket arrive as `::ffff:a.b.c.d`
+/// Bans, rate limits and the auth server's address are all kept as plain IPv4
+fn canonical(addr: SocketAddr) -> SocketAddr {
+	SocketAddr::new(addr.ip().to_canonical(), addr.port())
 }
 
 impl Hash for TUdpSocket {
@@ -24,16 +52,300 @@ impl PartialEq for TUdpSocket {
 impl Eq for TUdpSocket {}
 
 impl TUdpSocket {
-	pub async fn send_to<A: ToSocketAddrs>(&self, buf: &[u8], addr: A) -> io::Result<usize> {
-		self.sock.send_to(buf, addr).await
+	fn new(sock: UdpSocket, id: usize) -> io::Result<TUdpSocket> {
+		Ok(TUdpSocket {
+			v6: sock.local_addr()?.is_ipv6(),
+			sock: Arc::new(sock),
+			id,
+		})
+	}
+	/// Address the socket itself has to send to
+	fn dest(&self, addr: SocketAddr) -> SocketAddr {
+		match addr {
+			SocketAddr::V4(v4) if self.v6 => {
+				SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port())
+			}
+			_ => addr,
+		}
+	}
+	pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> io::Result<usize> {
+		self.sock.send_to(buf, self.dest(addr)).await
 	}
 	pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-		self.sock.recv_from(buf).await
+		let (len, addr) = self.sock.recv_from(buf).await?;
+		Ok((len, canonical(addr)))
+	}
+	pub fn id(&self) -> usize {
+		self.id
 	}
 	pub async fn bind<A: ToSocketAddrs>(addr: A, id: usize) -> io::Result<TUdpSocket> {
-		Ok(TUdpSocket {
-			sock: Arc::new(UdpSocket::bind(addr).await?),
-			id,
+		TUdpSocket::new(UdpSocket::bind(addr).await?, id)
+	}
+	/// Bind a socket for players
+	/// `v6_only` stops an IPv6 wildcard from also claiming the IPv4 port
+	pub fn listen(addr: SocketAddr, id: usize, v6_only: bool) -> io::Result<TUdpSocket> {
+		let sock = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+		if v6_only {
+			sock.set_only_v6(true)?;
+		}
+		sock.set_nonblocking(true)?;
+		sock.bind(&addr.into())?;
+		TUdpSocket::new(UdpSocket::from_std(sock.into())?, id)
+	}
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.sock.local_addr()
+	}
+
+	/// Read as many datagrams as are waiting, up to the batch size, with one system call
+	#[cfg(target_os = "linux")]
+	pub async fn recv_batch(&self, batch: &mut RecvBatch) -> io::Result<usize> {
+		batch.prepare_recv();
+		let fd = self.sock.as_raw_fd();
+		let count = self
+			.sock
+			.async_io(Interest::READABLE, || batch.recvmmsg(fd))
+			.await?;
+		batch.count = count;
+		Ok(count)
+	}
+
+	/// Send every datagram read into the batch to one address
+	#[cfg(target_os = "linux")]
+	pub async fn send_batch(&self, batch: &mut RecvBatch, addr: SocketAddr) -> io::Result<()> {
+		batch.prepare_send(self.dest(addr));
+		let fd = self.sock.as_raw_fd();
+		let mut sent = 0;
+		// The kernel may take only part of the batch when the send buffer fills up
+		while sent < batch.count {
+			sent += self
+				.sock
+				.async_io(Interest::WRITABLE, || batch.sendmmsg(fd, sent))
+				.await?;
+		}
+		Ok(())
+	}
+}
+
+/// Buffers for moving several datagrams per `recvmmsg` or `sendmmsg` call
+/// Allocated once and reused for every batch
+#[cfg(target_os = "linux")]
+pub struct RecvBatch {
+	bufs: Vec<Vec<u8>>,
+	/// Sender of each datagram
+	addrs: Vec<libc::sockaddr_storage>,
+	/// Destination when the batch is sent on
+	dest: libc::sockaddr_storage,
+	iovecs: Vec<libc::iovec>,
+	headers: Vec<libc::mmsghdr>,
+	/// Datagrams read by the last call
+	count: usize,
+}
+
+// The raw pointers only ever point into buffers owned by the batch
+#[cfg(target_os = "linux")]
+unsafe impl Send for RecvBatch {}
+#[cfg(target_os = "linux")]
+unsafe impl Sync for RecvBatch {}
+
+#[cfg(target_os = "linux")]
+impl RecvBatch {
+	pub fn new(size: usize, buf_size: usize) -> RecvBatch {
+		// All zero is a valid empty value for these C structs
+		RecvBatch {
+			bufs: vec![vec![0; buf_size]; size],
+			addrs: vec![unsafe { mem::zeroed() }; size],
+			dest: unsafe { mem::zeroed() },
+			iovecs: vec![
+				libc::iovec {
+					iov_base: ptr::null_mut(),
+					iov_len: 0,
+				};
+				size
+			],
+			headers: vec![unsafe { mem::zeroed() }; size],
+			count: 0,
+		}
+	}
+
+	/// Point every header at its buffer and sender address
+	/// Redone before each read since the kernel overwrites the lengths
+	fn prepare_recv(&mut self) {
+		for i in 0..self.headers.len() {
+			self.iovecs[i].iov_base = self.bufs[i].as_mut_ptr().cast();
+			self.iovecs[i].iov_len = self.bufs[i].len();
+			let hdr = &mut self.headers[i].msg_hdr;
+			hdr.msg_name = (&mut self.addrs[i] as *mut libc::sockaddr_storage).cast();
+			hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as _;
+			hdr.msg_iov = &mut self.iovecs[i];
+			hdr.msg_iovlen = 1;
+			self.headers[i].msg_len = 0;
+		}
+	}
+
+	fn recvmmsg(&mut self, fd: RawFd) -> io::Result<usize> {
+		// Every header points into buffers owned by the batch
+		let n = unsafe {
+			libc::recvmmsg(
+				fd,
+				self.headers.as_mut_ptr(),
+				self.headers.len() as _,
+				libc::MSG_DONTWAIT as _,
+				ptr::null_mut(),
+			)
+		};
+		if n < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(n as usize)
+		}
+	}
+
+	/// Send the datagrams from `from` onwards
+	fn sendmmsg(&mut self, fd: RawFd, from: usize) -> io::Result<usize> {
+		let n = unsafe {
+			libc::sendmmsg(
+				fd,
+				self.headers[from..].as_mut_ptr(),
+				(self.count - from) as _,
+				libc::MSG_DONTWAIT as _,
+			)
+		};
+		if n < 0 {
+			Err(io::Error::last_os_error())
+		} else {
+			Ok(n as usize)
+		}
+	}
+
+	/// Trim each buffer to what was read and address it to `addr`
+	fn prepare_send(&mut self, addr: SocketAddr) {
+		let len = write_sockaddr(addr, &mut self.dest);
+		for i in 0..self.count {
+			self.iovecs[i].iov_len = self.headers[i].msg_len as usize;
+			let hdr = &mut self.headers[i].msg_hdr;
+			hdr.msg_name = (&mut self.dest as *mut libc::sockaddr_storage).cast();
+			hdr.msg_namelen = len;
+		}
+	}
+
+	/// Payloads and senders of the datagrams read by the last call
+	pub fn iter(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+		(0..self.count).filter_map(move |i| {
+			let len = self.headers[i].msg_len as usize;
+			Some((
+				&self.bufs[i][..len],
+				canonical(read_sockaddr(&self.addrs[i])?),
+			))
+		})
+	}
+
+	/// Datagrams read by the last call
+	pub fn received(&self) -> usize {
+		self.count
+	}
+
+	/// Bytes read by the last call
+	pub fn bytes(&self) -> usize {
+		self.headers[..self.count]
+			.iter()
+			.map(|h| h.msg_len as usize)
+			.sum()
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn read_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+	match storage.ss_family as libc::c_int {
+		libc::AF_INET => {
+			let sin =
+				unsafe { &*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in>() };
+			Some(SocketAddr::from((
+				Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+				u16::from_be(sin.sin_port),
+			)))
+		}
+		libc::AF_INET6 => {
+			let sin6 = unsafe {
+				&*(storage as *const libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+			};
+			Some(SocketAddr::V6(SocketAddrV6::new(
+				Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+				u16::from_be(sin6.sin6_port),
+				sin6.sin6_flowinfo,
+				sin6.sin6_scope_id,
+			)))
+		}
+		_ => None,
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn write_sockaddr(addr: SocketAddr, storage: &mut libc::sockaddr_storage) -> libc::socklen_t {
+	*storage = unsafe { mem::zeroed() };
+	match addr {
+		SocketAddr::V4(a) => {
+			let sin = unsafe {
+				&mut *(storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in>()
+			};
+			sin.sin_family = libc::AF_INET as _;
+			sin.sin_port = a.port().to_be();
+			sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+			mem::size_of::<libc::sockaddr_in>() as _
+		}
+		SocketAddr::V6(a) => {
+			let sin6 = unsafe {
+				&mut *(storage as *mut libc::sockaddr_storage).cast::<libc::sockaddr_in6>()
+			};
+			sin6.sin6_family = libc::AF_INET6 as _;
+			sin6.sin6_port = a.port().to_be();
+			sin6.sin6_flowinfo = a.flowinfo();
+			sin6.sin6_addr.s6_addr = a.ip().octets();
+			sin6.sin6_scope_id = a.scope_id();
+			mem::size_of::<libc::sockaddr_in6>() as _
+		}
+	}
+}
+
+#[cfg(test)]
+impl TUdpSocket {
+	/// Bind `n` loopback sockets numbered from 0 on a runtime of their own
+	pub fn bind_for_tests(n: usize) -> Vec<TUdpSocket> {
+		tokio::runtime::Runtime::new().unwrap().block_on(async {
+			let mut socks = Vec::new();
+			for i in 0..n {
+				socks.push(TUdpSocket::bind("127.0.0.1:0", i).await.unwrap());
+			}
+			socks
 		})
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// IPv4 peers of a dual-stack socket look the same as on an IPv4 socket
+	#[tokio::test]
+	async fn dual_stack_peers_are_plain_ipv4() {
+		let listener = TUdpSocket::listen("[::]:0".parse().unwrap(), 0, false).unwrap();
+		let port = listener.local_addr().unwrap().port();
+		let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let mut buf = [0; 16];
+		client.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+		let (_, from) = listener.recv_from(&mut buf).await.unwrap();
+		assert_eq!(from, client.local_addr().unwrap());
+		listener.send_to(b"pong", from).await.unwrap();
+		let (len, _) = client.recv_from(&mut buf).await.unwrap();
+		assert_eq!(&buf[..len], b"pong");
+		#[cfg(target_os = "linux")]
+		{
+			let mut batch = RecvBatch::new(4, 16);
+			client.send_to(b"ping", ("127.0.0.1", port)).await.unwrap();
+			listener.recv_batch(&mut batch).await.unwrap();
+			assert_eq!(batch.iter().next().unwrap().1, from);
+			listener.send_batch(&mut batch, from).await.unwrap();
+			let (len, _) = client.recv_from(&mut buf).await.unwrap();
+			assert_eq!(&buf[..len], b"ping");
+		}
+	}
+}